@@ -0,0 +1,5 @@
+create table if not exists users
+(
+    telegram_id bigint primary key,
+    name        text not null
+);
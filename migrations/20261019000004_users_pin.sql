@@ -0,0 +1,4 @@
+alter table users
+    add column if not exists pin_hash text,
+    add column if not exists pin_failed_attempts integer not null default 0,
+    add column if not exists pin_locked_until bigint;
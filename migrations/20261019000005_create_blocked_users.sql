@@ -0,0 +1,5 @@
+create table if not exists blocked_users
+(
+    telegram_id bigint primary key,
+    blocked_at  timestamptz not null default now()
+);
@@ -0,0 +1,10 @@
+create table if not exists schedules
+(
+    id               serial primary key,
+    cron             text        not null,
+    action           text        not null check (action in ('open', 'light')),
+    device           text        not null,
+    duration_minutes integer,
+    created_by       bigint      not null,
+    created_at       timestamptz not null default now()
+);
@@ -0,0 +1,335 @@
+use crate::bot::{
+    night_approval_now, perform_gate_action, GateAction, GateActionOutcome, Requester,
+};
+use crate::database::{DbError, DbUser};
+use crate::hardware::RawHardware;
+use crate::State;
+use crate::{health, metrics};
+use axum::extract::{FromRequestParts, Path, State as AxumState};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{async_trait, Json, Router};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// User authenticated by the `Authorization: Bearer <token>` header.
+struct ApiUser(DbUser);
+
+#[async_trait]
+impl<T: RawHardware> FromRequestParts<Arc<State<T>>> for ApiUser {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<State<T>>,
+    ) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(axum::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        match state.db.get_user_by_api_token(token).await {
+            Ok(Some(user)) => Ok(ApiUser(user)),
+            Ok(None) => {
+                warn!("Unauthorized API request");
+                Err(StatusCode::UNAUTHORIZED)
+            }
+            Err(e) => Err(database_failure(e)),
+        }
+    }
+}
+
+fn database_failure(e: DbError) -> StatusCode {
+    error!("{}", e);
+    match e {
+        DbError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ActionResponse {
+    outcome: &'static str,
+}
+
+impl From<GateActionOutcome> for ActionResponse {
+    fn from(outcome: GateActionOutcome) -> Self {
+        let outcome = match outcome {
+            GateActionOutcome::Unlocked => "unlocked",
+            GateActionOutcome::SpotlightTurnedOn => "spotlight_turned_on",
+            GateActionOutcome::SpotlightAlreadyOn => "spotlight_already_on",
+            GateActionOutcome::SpotlightExtended => "spotlight_extended",
+            GateActionOutcome::SpotlightTurnedOff => "spotlight_turned_off",
+            GateActionOutcome::UnknownDevice => "unknown_device",
+            GateActionOutcome::NotPermitted => "not_permitted",
+        };
+        Self { outcome }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct LightStatus {
+    id: String,
+    name: String,
+    on: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct GateStatus {
+    id: String,
+    name: String,
+    /// `None` when the gate has no sensor.
+    closed: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct StatusResponse {
+    /// State of the first light, from before there could be several.
+    spotlight_on: bool,
+    lights: Vec<LightStatus>,
+    gates: Vec<GateStatus>,
+}
+
+/// The `/api/gate` and `/api/spotlight` routes act on the first gate and light.
+pub fn router<T: RawHardware>() -> Router<Arc<State<T>>> {
+    Router::new()
+        .route("/api/gate/open", post(open_gate::<T>))
+        .route("/api/spotlight/on", post(spotlight_on::<T>))
+        .route("/api/spotlight/off", post(spotlight_off::<T>))
+        .route("/api/gates/:id/open", post(open_gate_by_id::<T>))
+        .route("/api/lights/:id/on", post(light_on_by_id::<T>))
+        .route("/api/lights/:id/off", post(light_off_by_id::<T>))
+        .route("/api/status", get(status::<T>))
+}
+
+pub async fn serve<T: RawHardware>(state: Arc<State<T>>, addr: SocketAddr) {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .unwrap_or_else(|e| panic!("Could not bind HTTP API to {addr}: {e}"));
+    info!("HTTP API listening on {addr}");
+    let app = router()
+        .merge(health::router())
+        .merge(metrics::router())
+        .with_state(state);
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("HTTP API stopped: {e}");
+    }
+}
+
+async fn act<T: RawHardware>(
+    state: Arc<State<T>>,
+    ApiUser(user): ApiUser,
+    action: GateAction,
+) -> Result<Json<ActionResponse>, StatusCode> {
+    let requester = Requester::User { user, via: "http" };
+    match perform_gate_action(&state, &requester, action).await {
+        GateActionOutcome::UnknownDevice => Err(StatusCode::NOT_FOUND),
+        GateActionOutcome::NotPermitted => Err(StatusCode::FORBIDDEN),
+        outcome => Ok(Json(outcome.into())),
+    }
+}
+
+/// Opening over HTTP can't ask for a PIN or wait for someone to approve it, so users that need
+/// either are refused instead of skipping those checks.
+async fn open<T: RawHardware>(
+    state: Arc<State<T>>,
+    ApiUser(user): ApiUser,
+    gate: usize,
+) -> Result<Json<ActionResponse>, StatusCode> {
+    let needs_approval = {
+        let config = state.config.read().await;
+        night_approval_now(&config.bot, config.logging.utc_offset_hours, user.role).is_some()
+    };
+    if needs_approval {
+        warn!(
+            "Refusing to open over HTTP for {} without approval",
+            user.name
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let pin_state = state
+        .db
+        .get_pin_state(user.telegram_id)
+        .await
+        .map_err(database_failure)?;
+    if pin_state.pin_hash.is_some() {
+        warn!(
+            "Refusing to open over HTTP for {} without their PIN",
+            user.name
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+    act(state, ApiUser(user), GateAction::Open(gate)).await
+}
+
+async fn gate_index<T: RawHardware>(state: &State<T>, id: &str) -> Result<usize, StatusCode> {
+    let config = state.config.read().await;
+    config
+        .hardware
+        .gates
+        .iter()
+        .position(|gate| gate.id == id)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn light_index<T: RawHardware>(state: &State<T>, id: &str) -> Result<usize, StatusCode> {
+    let config = state.config.read().await;
+    config
+        .hardware
+        .lights
+        .iter()
+        .position(|light| light.id == id)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn open_gate<T: RawHardware>(
+    AxumState(state): AxumState<Arc<State<T>>>,
+    user: ApiUser,
+) -> Result<Json<ActionResponse>, StatusCode> {
+    open(state, user, 0).await
+}
+
+async fn spotlight_on<T: RawHardware>(
+    AxumState(state): AxumState<Arc<State<T>>>,
+    user: ApiUser,
+) -> Result<Json<ActionResponse>, StatusCode> {
+    act(state, user, GateAction::SpotlightOn(0)).await
+}
+
+async fn spotlight_off<T: RawHardware>(
+    AxumState(state): AxumState<Arc<State<T>>>,
+    user: ApiUser,
+) -> Result<Json<ActionResponse>, StatusCode> {
+    act(state, user, GateAction::SpotlightOff(0)).await
+}
+
+async fn open_gate_by_id<T: RawHardware>(
+    AxumState(state): AxumState<Arc<State<T>>>,
+    Path(id): Path<String>,
+    user: ApiUser,
+) -> Result<Json<ActionResponse>, StatusCode> {
+    let gate = gate_index(&state, &id).await?;
+    open(state, user, gate).await
+}
+
+async fn light_on_by_id<T: RawHardware>(
+    AxumState(state): AxumState<Arc<State<T>>>,
+    Path(id): Path<String>,
+    user: ApiUser,
+) -> Result<Json<ActionResponse>, StatusCode> {
+    let light = light_index(&state, &id).await?;
+    act(state, user, GateAction::SpotlightOn(light)).await
+}
+
+async fn light_off_by_id<T: RawHardware>(
+    AxumState(state): AxumState<Arc<State<T>>>,
+    Path(id): Path<String>,
+    user: ApiUser,
+) -> Result<Json<ActionResponse>, StatusCode> {
+    let light = light_index(&state, &id).await?;
+    act(state, user, GateAction::SpotlightOff(light)).await
+}
+
+async fn status<T: RawHardware>(
+    AxumState(state): AxumState<Arc<State<T>>>,
+    ApiUser(user): ApiUser,
+) -> Json<StatusResponse> {
+    let mut lights: Vec<LightStatus> = state
+        .config
+        .read()
+        .await
+        .hardware
+        .lights
+        .iter()
+        .enumerate()
+        .map(|(index, light)| LightStatus {
+            id: light.id.clone(),
+            name: light.name.clone(),
+            on: state.hw.is_spotlight_on(index),
+        })
+        .collect();
+    let spotlight_on = lights.first().is_some_and(|light| light.on);
+    lights.retain(|light| user.can_operate(&light.id));
+    let gates_config = state.config.read().await.hardware.gates.clone();
+    let mut gates = vec![];
+    for (index, gate) in gates_config.into_iter().enumerate() {
+        if user.can_operate(&gate.id) {
+            gates.push(GateStatus {
+                closed: state.hw.is_gate_closed(index).await,
+                id: gate.id,
+                name: gate.name,
+            });
+        }
+    }
+    Json(StatusResponse {
+        spotlight_on,
+        lights,
+        gates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::UserRole;
+    use crate::hardware::simulator::Output;
+    use crate::harness::{self, Harness, RESIDENT};
+
+    async fn open_as(harness: &Harness, role: UserRole) -> Result<&'static str, StatusCode> {
+        let user = harness::user(RESIDENT, "Resident", role);
+        open_gate_by_id(
+            AxumState(Arc::clone(&harness.state)),
+            Path("gate".to_string()),
+            ApiUser(user),
+        )
+        .await
+        .map(|Json(response)| response.outcome)
+    }
+
+    fn pulses(harness: &Harness) -> usize {
+        harness
+            .hardware
+            .transitions(Output::Gate(0))
+            .iter()
+            .filter(|transition| transition.high)
+            .count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_for_users_without_a_pin() {
+        let harness = Harness::start().await;
+        assert_eq!(open_as(&harness, UserRole::Resident).await, Ok("unlocked"));
+        assert_eq!(pulses(&harness), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refuses_users_with_a_pin() {
+        let harness = Harness::start().await;
+        harness
+            .state
+            .db
+            .set_pin_hash(RESIDENT, Some("hash"))
+            .await
+            .unwrap();
+        assert_eq!(
+            open_as(&harness, UserRole::Resident).await,
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(pulses(&harness), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refuses_users_needing_a_night_approval() {
+        let harness = Harness::with_config(harness::config_with_night_approval()).await;
+        assert_eq!(
+            open_as(&harness, UserRole::Resident).await,
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(open_as(&harness, UserRole::Admin).await, Ok("unlocked"));
+        assert_eq!(pulses(&harness), 1);
+    }
+}
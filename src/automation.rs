@@ -0,0 +1,211 @@
+use crate::bot::{perform_gate_action, GateAction, Requester};
+use crate::config::{AutoLightConfig, SunEvent, SunScheduleConfig};
+use crate::cron::CronSpec;
+use crate::database::{DbSchedule, ScheduledAction};
+use crate::hardware::RawHardware;
+use crate::sun::{self, SunDay};
+use crate::State;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Turns on the lights in [AutoLightConfig::on_open_lights] if it is dark, unless they are
+/// already on for longer.
+pub async fn light_up_after_opening<T: RawHardware>(state: &State<T>) {
+    let (auto_light, lights) = {
+        let config = state.config.read().await;
+        match &config.auto_light {
+            Some(auto_light) => (auto_light.clone(), config.hardware.lights.clone()),
+            None => return,
+        }
+    };
+    if !sun::is_dark(Utc::now(), auto_light.latitude, auto_light.longitude) {
+        return;
+    }
+    let duration = auto_light.on_open();
+    for (index, light) in lights.iter().enumerate() {
+        if !auto_light.turns_on_after_opening(&light.id) {
+            continue;
+        }
+        if state
+            .hw
+            .spotlight_remaining(index)
+            .is_some_and(|remaining| remaining >= duration)
+        {
+            continue;
+        }
+        info!(
+            "Audit: automation after opening in the dark turning on {} for {:?}",
+            light.name, duration
+        );
+        state.hw.turn_on_spotlight(index, duration).await;
+    }
+}
+
+/// When the schedule next fires after `now`, looking a couple of days ahead since the sun may
+/// not rise or set near the poles.
+fn next_run(
+    schedule: &SunScheduleConfig,
+    auto_light: &AutoLightConfig,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let today = sun::local_date(now, auto_light.longitude);
+    (-1..=2)
+        .filter_map(|days| today.checked_add_signed(chrono::Duration::days(days)))
+        .filter_map(
+            |date| match sun::sun_day(date, auto_light.latitude, auto_light.longitude) {
+                SunDay::Rises { sunrise, sunset } => Some(match schedule.event {
+                    SunEvent::Sunrise => sunrise,
+                    SunEvent::Sunset => sunset,
+                }),
+                SunDay::AlwaysUp | SunDay::AlwaysDown => None,
+            },
+        )
+        .map(|event| event + chrono::Duration::minutes(schedule.offset_minutes))
+        .find(|run_at| *run_at > now)
+}
+
+/// Runs [AutoLightConfig::schedules] forever, through [perform_gate_action] so they are audited
+/// like manual actions.
+pub async fn run_sun_schedules<T: RawHardware>(state: Arc<State<T>>, auto_light: AutoLightConfig) {
+    if auto_light.schedules.is_empty() {
+        return;
+    }
+    // the sleep runs on the monotonic clock, so the wall clock may still be before the run
+    let mut last_run_at = None;
+    loop {
+        let now = Utc::now();
+        let after = last_run_at.map_or(now, |last_run_at: DateTime<Utc>| last_run_at.max(now));
+        let next = auto_light
+            .schedules
+            .iter()
+            .filter_map(|schedule| Some((next_run(schedule, &auto_light, after)?, schedule)))
+            .min_by_key(|(run_at, _)| *run_at);
+        let Some((run_at, schedule)) = next else {
+            warn!("No sunrise or sunset in the next days, checking the schedules again later");
+            tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+            continue;
+        };
+        info!("Next light schedule at {run_at} for {}", schedule.light);
+        tokio::time::sleep((run_at - now).to_std().unwrap_or_default()).await;
+        last_run_at = Some(run_at);
+        let light = state
+            .config
+            .read()
+            .await
+            .hardware
+            .lights
+            .iter()
+            .position(|light| light.id == schedule.light);
+        let Some(light) = light else {
+            warn!("Scheduled light {} is gone", schedule.light);
+            continue;
+        };
+        let requester = Requester::Automation(format!(
+            "{:?} {:+}min",
+            schedule.event, schedule.offset_minutes
+        ));
+        perform_gate_action(
+            &state,
+            &requester,
+            GateAction::SpotlightFor(light, schedule.duration()),
+        )
+        .await;
+    }
+}
+
+/// How long to wait before loading the schedules again after the DB failed.
+const SCHEDULES_RETRY: Duration = Duration::from_secs(60);
+
+/// Runs the schedules stored in the DB forever, loading them again whenever
+/// [State::schedules_changed] is notified. Like [run_sun_schedules], the actions go through
+/// [perform_gate_action].
+pub async fn run_schedules<T: RawHardware>(state: Arc<State<T>>) {
+    // local time of the last run, which the wall clock may not have reached yet after the sleep
+    let mut last_run_at = None;
+    loop {
+        let schedules = match state.db.schedules().await {
+            Ok(schedules) => schedules,
+            Err(e) => {
+                error!("Loading schedules: {e}");
+                tokio::select! {
+                    () = tokio::time::sleep(SCHEDULES_RETRY) => {}
+                    () = state.schedules_changed.notified() => {}
+                }
+                continue;
+            }
+        };
+        let schedules: Vec<(DbSchedule, CronSpec)> = schedules
+            .into_iter()
+            .filter_map(|schedule| match schedule.cron.parse() {
+                Ok(cron) => Some((schedule, cron)),
+                Err(e) => {
+                    warn!("Skipping schedule #{}: {e}", schedule.id);
+                    None
+                }
+            })
+            .collect();
+        let utc_offset_hours = state.config.read().await.logging.utc_offset_hours;
+        let offset = chrono::Duration::hours(utc_offset_hours.into());
+        let now = Utc::now();
+        let local_now = now.naive_utc() + offset;
+        let after = last_run_at.map_or(local_now, |last_run_at: NaiveDateTime| {
+            last_run_at.max(local_now)
+        });
+        let Some(run_at) = schedules
+            .iter()
+            .filter_map(|(_, cron)| cron.next_after(after))
+            .min()
+        else {
+            state.schedules_changed.notified().await;
+            continue;
+        };
+        let wait = (run_at - offset - now.naive_utc())
+            .to_std()
+            .unwrap_or_default();
+        tokio::select! {
+            () = tokio::time::sleep(wait) => {}
+            () = state.schedules_changed.notified() => continue,
+        }
+        last_run_at = Some(run_at);
+        for (schedule, _) in schedules
+            .iter()
+            .filter(|(_, cron)| cron.next_after(after) == Some(run_at))
+        {
+            run_schedule(&state, schedule).await;
+        }
+    }
+}
+
+async fn run_schedule<T: RawHardware>(state: &State<T>, schedule: &DbSchedule) {
+    let action = {
+        let config = state.config.read().await;
+        match schedule.action {
+            ScheduledAction::Open => config
+                .hardware
+                .gates
+                .iter()
+                .position(|gate| gate.id == schedule.device)
+                .map(GateAction::Open),
+            ScheduledAction::Light => config
+                .hardware
+                .lights
+                .iter()
+                .position(|light| light.id == schedule.device)
+                .map(|light| {
+                    let minutes = schedule.duration_minutes.unwrap_or_default().max(1) as u64;
+                    GateAction::SpotlightFor(light, Duration::from_secs(minutes * 60))
+                }),
+        }
+    };
+    let Some(action) = action else {
+        warn!(
+            "Schedule #{} refers to unknown device {}",
+            schedule.id, schedule.device
+        );
+        return;
+    };
+    let requester = Requester::Automation(format!("schedule #{}", schedule.id));
+    perform_gate_action(state, &requester, action).await;
+}
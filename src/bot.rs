@@ -1,79 +1,471 @@
-use crate::bot::RequestedAction::{ConfirmOpen, PrepareOpen, TurnOnLight, Unclassified};
-use crate::database::DbUser;
+use crate::automation;
+use crate::bot::callback::{CallbackAction, CallbackError, CallbackPayload};
+use crate::bot::RequestedAction::{
+    AddSchedule, AnswerApproval, ConfirmOpen, Light, ListSchedules, PinUsage, PrepareOpen, Reload,
+    RemoveSchedule, ReviewStranger, ScheduleUsage, SetLocale, SetPin, Text, Unclassified,
+};
+use crate::config::{BotConfig, HardwareConfig, LightConfig, NightApprovalConfig};
+use crate::cron::CronSpec;
+use crate::database::{
+    hash_pin, verify_pin, DbError, DbSchedule, DbUser, PinState, ScheduledAction, UserRole,
+};
 use crate::hardware::RawHardware;
+use crate::i18n::Locale;
+use crate::metrics;
+use crate::pending::{Pending, PendingAction};
+use crate::rate_limit::{Decision, LimitKind};
 use crate::telegram::{
-    ButtonAnswer, Content, DeletableOutgoingMessage, OutgoingMessage, TelegramResponse,
-    TelegramUpdate,
+    Button, ButtonAnswer, Content, DeletableOutgoingMessage, Outbox, OutgoingMessage,
+    TelegramResponse, TelegramUpdate,
 };
 use crate::State;
+use chrono::Timelike;
+use std::fmt::{Debug, Formatter};
 use std::sync::Arc;
 use std::time::Duration;
+use thiserror::Error;
 use tracing::{error, info, warn};
 
+pub mod callback;
+
+/// Text that may be a PIN, kept out of the logs.
+struct Secret(String);
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "***")
+    }
+}
+
 #[derive(Debug)]
 pub struct UserRequest {
     user_id: u64,
+    language_code: Option<String>,
+    /// The user's message the request came in, None for buttons.
+    message_id: Option<i32>,
     action: RequestedAction,
 }
 
 #[derive(Debug)]
 enum RequestedAction {
-    TurnOnLight { callback_id: String },
-    PrepareOpen { callback_id: String },
-    ConfirmOpen { callback_id: String },
+    /// Any of the light buttons, always a spotlight [GateAction].
+    Light {
+        callback_id: String,
+        action: GateAction,
+    },
+    PrepareOpen {
+        callback_id: String,
+        gate: usize,
+    },
+    ConfirmOpen {
+        callback_id: String,
+        payload: Option<CallbackPayload>,
+    },
+    AnswerApproval {
+        callback_id: String,
+        payload: Option<CallbackPayload>,
+        approved: bool,
+    },
+    ReviewStranger {
+        callback_id: String,
+        payload: Option<CallbackPayload>,
+        grant: bool,
+    },
+    Reload,
+    SetLocale(String),
+    SetPin {
+        current: Option<Secret>,
+        new: Secret,
+    },
+    PinUsage,
+    /// The words after `/schedule add`.
+    AddSchedule(Vec<String>),
+    ListSchedules,
+    RemoveSchedule(String),
+    ScheduleUsage,
+    /// A message that is not a command, the PIN when one was asked for.
+    Text(Secret),
     Unclassified,
 }
 
 impl RequestedAction {
-    fn default_buttons() -> Vec<String> {
-        vec!["Abrir".to_string(), "Luz 3min".to_string()]
+    /// A button for each gate and light the user can operate, named only when there are several.
+    fn default_buttons(
+        config: &BotConfig,
+        hardware: &HardwareConfig,
+        user: &DbUser,
+        locale: Locale,
+    ) -> Vec<Button> {
+        let several_gates = hardware.gates.len() > 1;
+        let several_lights = hardware.lights.len() > 1;
+        let gates = hardware
+            .gates
+            .iter()
+            .enumerate()
+            .filter(|(_, gate)| user.can_operate(&gate.id))
+            .filter_map(|(index, gate)| {
+                callback_button(
+                    if several_gates {
+                        locale.open_gate_button(&gate.name)
+                    } else {
+                        locale.open_button().to_string()
+                    },
+                    CallbackPayload::new(CallbackAction::PrepareOpen).with_target(index as i64),
+                )
+            });
+        let lights = hardware
+            .lights
+            .iter()
+            .enumerate()
+            .filter(|(_, light)| user.can_operate(&light.id))
+            .filter_map(|(index, light)| {
+                let minutes = light.duration(config).as_secs() / 60;
+                callback_button(
+                    if several_lights {
+                        locale.named_light_button(&light.name, minutes)
+                    } else {
+                        locale.light_button(minutes)
+                    },
+                    CallbackPayload::new(CallbackAction::Light).with_target(index as i64),
+                )
+            });
+        gates.chain(lights).collect()
     }
-    fn confirm_open_button() -> Vec<String> {
-        vec!["Confirmar Abrir".to_string()]
+    fn confirm_open_button(payload: CallbackPayload, locale: Locale) -> Vec<Button> {
+        callback_button(locale.confirm_open_button().to_string(), payload)
+            .into_iter()
+            .collect()
     }
-    fn from_button_text(button: &str, callback_query_id: String) -> RequestedAction {
-        match button {
-            "Luz 3min" => TurnOnLight {
+    fn from_callback_data(callback_data: &str, callback_query_id: String) -> RequestedAction {
+        let now = chrono::Utc::now().timestamp();
+        let (action, payload) = match CallbackPayload::parse(callback_data, now) {
+            Ok(payload) => (payload.action, Some(payload)),
+            Err(CallbackError::Malformed(_)) => match legacy_callback_action(callback_data) {
+                Some(action) => (action, None),
+                None => {
+                    warn!("Got weird action: {callback_data}");
+                    return Unclassified;
+                }
+            },
+            Err(e) => {
+                warn!("Rejecting button: {e}");
+                return Unclassified;
+            }
+        };
+        // buttons from before there were several devices operate the first one
+        let Ok(target) = usize::try_from(payload.map_or(0, |payload| payload.target)) else {
+            warn!("Rejecting button with target: {callback_data}");
+            return Unclassified;
+        };
+        match action {
+            CallbackAction::Light => Light {
+                callback_id: callback_query_id,
+                action: GateAction::SpotlightOn(target),
+            },
+            CallbackAction::ExtendLight => Light {
                 callback_id: callback_query_id,
+                action: GateAction::SpotlightExtend(target),
             },
-            "Abrir" => PrepareOpen {
+            CallbackAction::LightOff => Light {
                 callback_id: callback_query_id,
+                action: GateAction::SpotlightOff(target),
             },
-            "Confirmar Abrir" => ConfirmOpen {
+            CallbackAction::LightFor => Light {
                 callback_id: callback_query_id,
+                action: GateAction::SpotlightFor(
+                    target,
+                    Duration::from_secs(
+                        u64::from(payload.map_or(0, |payload| payload.amount)) * 60,
+                    ),
+                ),
             },
-            x => {
-                warn!("Got weird action: {x}");
-                Unclassified
+            CallbackAction::PrepareOpen => PrepareOpen {
+                callback_id: callback_query_id,
+                gate: target,
+            },
+            CallbackAction::ConfirmOpen => ConfirmOpen {
+                callback_id: callback_query_id,
+                payload,
+            },
+            CallbackAction::ApproveOpen => AnswerApproval {
+                callback_id: callback_query_id,
+                payload,
+                approved: true,
+            },
+            CallbackAction::DenyOpen => AnswerApproval {
+                callback_id: callback_query_id,
+                payload,
+                approved: false,
+            },
+            CallbackAction::GrantGuest => ReviewStranger {
+                callback_id: callback_query_id,
+                payload,
+                grant: true,
+            },
+            CallbackAction::Block => ReviewStranger {
+                callback_id: callback_query_id,
+                payload,
+                grant: false,
+            },
+        }
+    }
+    /// Actions that touch the hardware or could be used to guess a PIN, see [LimitKind::GateAction].
+    fn is_gate_action(&self) -> bool {
+        matches!(
+            self,
+            Light { .. } | PrepareOpen { .. } | ConfirmOpen { .. } | SetPin { .. } | Text(_)
+        )
+    }
+    fn callback_id(&self) -> Option<&str> {
+        match self {
+            Light { callback_id, .. }
+            | PrepareOpen { callback_id, .. }
+            | ConfirmOpen { callback_id, .. }
+            | AnswerApproval { callback_id, .. }
+            | ReviewStranger { callback_id, .. } => Some(callback_id),
+            _ => None,
+        }
+    }
+    fn from_message(text: &str) -> RequestedAction {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words[..] {
+            ["/reload"] => Reload,
+            ["/language", code, ..] => SetLocale(code.to_string()),
+            ["/pin", new] => SetPin {
+                current: None,
+                new: Secret(new.to_string()),
+            },
+            ["/pin", current, new] => SetPin {
+                current: Some(Secret(current.to_string())),
+                new: Secret(new.to_string()),
+            },
+            ["/pin", ..] => PinUsage,
+            ["/schedule", "add", ref words @ ..] => {
+                AddSchedule(words.iter().map(|word| word.to_string()).collect())
+            }
+            ["/schedule", "list"] => ListSchedules,
+            ["/schedule", "remove", id] => RemoveSchedule(id.to_string()),
+            ["/schedule", ..] => ScheduleUsage,
+            [command, ..] if command.starts_with('/') => Unclassified,
+            _ => Text(Secret(text.trim().to_string())),
+        }
+    }
+}
+
+/// Messages sent before the callback data was versioned carry either the Portuguese label or a
+/// plain action name instead.
+fn legacy_callback_action(callback_data: &str) -> Option<CallbackAction> {
+    match callback_data {
+        "light" => Some(CallbackAction::Light),
+        light if light.starts_with("Luz ") => Some(CallbackAction::Light),
+        "open" | "Abrir" => Some(CallbackAction::PrepareOpen),
+        "confirm_open" | "Confirmar Abrir" => Some(CallbackAction::ConfirmOpen),
+        _ => None,
+    }
+}
+
+/// Hardware actions shared by every interface (Telegram, HTTP API, MQTT), so they all go through
+/// the same audit log. Gates and lights are indexes into [HardwareConfig::gates] and
+/// [HardwareConfig::lights].
+#[derive(Debug, Clone, Copy)]
+pub enum GateAction {
+    Open(usize),
+    /// Turns the spotlight on for its configured duration, unless it is already on.
+    SpotlightOn(usize),
+    /// Keeps the spotlight on for the given time from now.
+    SpotlightFor(usize, Duration),
+    /// Adds the configured duration to the time the spotlight stays on.
+    SpotlightExtend(usize),
+    SpotlightOff(usize),
+}
+
+impl GateAction {
+    fn light(self) -> Option<usize> {
+        match self {
+            GateAction::Open(_) => None,
+            GateAction::SpotlightOn(light)
+            | GateAction::SpotlightFor(light, _)
+            | GateAction::SpotlightExtend(light)
+            | GateAction::SpotlightOff(light) => Some(light),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateActionOutcome {
+    Unlocked,
+    SpotlightTurnedOn,
+    SpotlightAlreadyOn,
+    SpotlightExtended,
+    SpotlightTurnedOff,
+    UnknownDevice,
+    /// The user's `devices` don't include it.
+    NotPermitted,
+}
+
+/// Who asked for a [GateAction], as recorded in the audit log.
+#[derive(Debug, Clone)]
+pub enum Requester {
+    User { user: DbUser, via: &'static str },
+    Automation(String),
+}
+
+impl std::fmt::Display for Requester {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Requester::User { user, via } => {
+                write!(f, "{} ({}) via {}", user.name, user.telegram_id, via)
             }
+            Requester::Automation(name) => write!(f, "automation {}", name),
+        }
+    }
+}
+
+/// Performs the action for an already authenticated requester, if they can operate the device.
+pub async fn perform_gate_action<T: RawHardware>(
+    state: &State<T>,
+    requester: &Requester,
+    action: GateAction,
+) -> GateActionOutcome {
+    let (device_id, device_name, spotlight_duration) = {
+        let config = state.config.read().await;
+        let device = match action {
+            GateAction::Open(gate) => config
+                .hardware
+                .gates
+                .get(gate)
+                .map(|gate| (gate.id.clone(), gate.name.clone(), Duration::ZERO)),
+            _ => action
+                .light()
+                .and_then(|light| config.hardware.lights.get(light))
+                .map(|light| {
+                    let duration = light.duration(&config.bot);
+                    (light.id.clone(), light.name.clone(), duration)
+                }),
+        };
+        match device {
+            Some(device) => device,
+            None => {
+                warn!("{} asked for {:?} on an unknown device", requester, action);
+                return GateActionOutcome::UnknownDevice;
+            }
+        }
+    };
+    if let Requester::User { user, .. } = requester {
+        if !user.can_operate(&device_id) {
+            warn!(
+                "Audit: {} is not allowed to operate {}",
+                requester, device_id
+            );
+            return GateActionOutcome::NotPermitted;
+        }
+    }
+    info!(
+        "Audit: {} performing {:?} on {}",
+        requester, action, device_name
+    );
+    match action {
+        GateAction::Open(gate) => {
+            state.hw.unlock_gate(gate).await;
+            metrics::GATE_UNLOCKS.with_label_values(&[&device_id]).inc();
+            automation::light_up_after_opening(state).await;
+            GateActionOutcome::Unlocked
+        }
+        GateAction::SpotlightOn(light) => {
+            if state.hw.is_spotlight_on(light) {
+                GateActionOutcome::SpotlightAlreadyOn
+            } else {
+                state.hw.turn_on_spotlight(light, spotlight_duration).await;
+                GateActionOutcome::SpotlightTurnedOn
+            }
+        }
+        GateAction::SpotlightFor(light, duration) => {
+            state.hw.turn_on_spotlight(light, duration).await;
+            GateActionOutcome::SpotlightTurnedOn
+        }
+        GateAction::SpotlightExtend(light) => {
+            state.hw.extend_spotlight(light, spotlight_duration).await;
+            GateActionOutcome::SpotlightExtended
+        }
+        GateAction::SpotlightOff(light) => {
+            state.hw.turn_off_spotlight(light).await;
+            GateActionOutcome::SpotlightTurnedOff
         }
     }
 }
 
 pub async fn handle_update<T: RawHardware>(
     state: Arc<State<T>>,
+    outbox: &Outbox,
     update: TelegramUpdate,
 ) -> Option<TelegramResponse> {
+    let _timer = metrics::UPDATE_HANDLING_SECONDS.start_timer();
     // authorize user
     let user_id = i64::try_from(update.user_id).ok()?;
+    if state.rate_limiter.is_banned(user_id) || state.db.is_blocked(user_id).await {
+        metrics::UPDATES_HANDLED
+            .with_label_values(&["banned"])
+            .inc();
+        return None;
+    }
+    // users missing from the cache cost a DB query, strangers could use that to hammer the DB
+    if !state.db.is_cached(user_id).await {
+        match state.rate_limiter.check(user_id, LimitKind::Unknown) {
+            Decision::Allowed => {}
+            Decision::Limited => {
+                metrics::UPDATES_HANDLED
+                    .with_label_values(&["rate_limited"])
+                    .inc();
+                return None;
+            }
+            Decision::Banned => {
+                warn!("Audit: banned unknown user {} for spamming", user_id);
+                alert_admins_of_ban(&state, outbox, user_id).await;
+                metrics::UPDATES_HANDLED
+                    .with_label_values(&["banned"])
+                    .inc();
+                return None;
+            }
+        }
+    }
     let user = match state.db.get_user(user_id).await {
         Ok(user) => user,
         Err(e) => {
-            error!("{}", e);
-            return None;
+            metrics::UPDATES_HANDLED
+                .with_label_values(&["db_error"])
+                .inc();
+            let locale = Locale::choose(None, update.language_code.as_deref());
+            let callback_id = match update.content {
+                Content::Button {
+                    callback_query_id, ..
+                } => Some(callback_query_id),
+                Content::Message { .. } => None,
+            };
+            return Some(database_failure(e, user_id, callback_id, locale));
         }
     };
     let authorized_user = match user {
-        None => return Some(unauthorized(user_id)),
+        None => {
+            metrics::UPDATES_HANDLED
+                .with_label_values(&["unauthorized"])
+                .inc();
+            let locale = Locale::choose(None, update.language_code.as_deref());
+            notify_admins_of_stranger(&state, outbox, user_id, locale, &update).await;
+            return Some(unauthorized(user_id, locale));
+        }
         Some(authorized_user) => authorized_user,
     };
+    metrics::UPDATES_HANDLED
+        .with_label_values(&["authorized"])
+        .inc();
 
     let user_request = parse_user_request(update);
-    handle_user_request(state, user_request, authorized_user).await
+    handle_user_request(state, outbox, user_request, authorized_user).await
 }
 
 pub async fn handle_user_request<T: RawHardware>(
     state: Arc<State<T>>,
+    outbox: &Outbox,
     user_request: UserRequest,
     authorized_user: DbUser,
 ) -> Option<TelegramResponse> {
@@ -82,110 +474,1035 @@ pub async fn handle_user_request<T: RawHardware>(
         "Authorized User {} for request {:?}",
         authorized_user.name, user_request
     );
+    let is_admin = authorized_user.role == UserRole::Admin;
+    let locale = Locale::choose(
+        authorized_user.locale.as_deref(),
+        user_request.language_code.as_deref(),
+    );
+    let requester = Requester::User {
+        user: authorized_user.clone(),
+        via: "telegram",
+    };
+    let (bot_config, hardware, utc_offset_hours) = {
+        let config = state.config.read().await;
+        (
+            config.bot.clone(),
+            config.hardware.clone(),
+            config.logging.utc_offset_hours,
+        )
+    };
+    let default_buttons =
+        RequestedAction::default_buttons(&bot_config, &hardware, &authorized_user, locale);
+    let default_response = || default_message(user_id, default_buttons.clone(), locale);
+    let callback_id = user_request.action.callback_id().map(str::to_string);
+    let failed = |e: DbError| Some(database_failure(e, user_id, callback_id.clone(), locale));
+    let can_open = |gate: usize| {
+        hardware
+            .gates
+            .get(gate)
+            .is_some_and(|gate| authorized_user.can_operate(&gate.id))
+    };
+    let gate_name = |gate: usize| {
+        hardware
+            .gates
+            .get(gate)
+            .map_or("", |gate| gate.name.as_str())
+    };
+    // PINs shouldn't stay in the chat history, even when the request goes no further.
+    let holds_pin = match &user_request.action {
+        SetPin { .. } | PinUsage => true,
+        Text(_) => state.pending_actions.contains_for_user(user_id, |action| {
+            matches!(action, PendingAction::EnterPin { .. })
+        }),
+        _ => false,
+    };
+    if let Some(message_id) = user_request.message_id.filter(|_| holds_pin) {
+        outbox.delete(user_id, message_id);
+    }
+    if user_request.action.is_gate_action() {
+        match state.rate_limiter.check(user_id, LimitKind::GateAction) {
+            Decision::Allowed => {}
+            Decision::Limited => {
+                warn!("Rate limited {}", requester);
+                let message = locale.rate_limited().to_string();
+                return Some(match user_request.action.callback_id() {
+                    Some(callback_id) => button_answer(callback_id.to_string(), message),
+                    None => text_message(user_id, message),
+                });
+            }
+            Decision::Banned => {
+                warn!(
+                    "Audit: banned {} for {:?} after too many requests",
+                    requester,
+                    state.rate_limiter.ban_duration()
+                );
+                return None;
+            }
+        }
+    }
     let response = match user_request.action {
-        Unclassified => Some(default_message(user_id)),
-        TurnOnLight { callback_id } => {
-            if state.hw.is_spotlight_on().await {
-                Some(light_already_on_response(callback_id))
+        Unclassified => Some(default_response()),
+        Reload if !is_admin => {
+            warn!("Non admin {} tried to reload", requester);
+            Some(default_response())
+        }
+        AddSchedule(_) | ListSchedules | RemoveSchedule(_) | ScheduleUsage if !is_admin => {
+            warn!("Non admin {} tried to manage schedules", requester);
+            Some(default_response())
+        }
+        AddSchedule(words) => {
+            Some(add_schedule(&state, user_id, &requester, &words, &hardware, locale).await)
+        }
+        ListSchedules => match state.db.schedules().await {
+            Ok(schedules) if schedules.is_empty() => {
+                Some(text_message(user_id, locale.no_schedules().to_string()))
+            }
+            Ok(schedules) => {
+                let lines: Vec<String> = schedules.iter().map(describe_schedule).collect();
+                Some(text_message(user_id, lines.join("\n")))
+            }
+            Err(e) => failed(e),
+        },
+        RemoveSchedule(id) => {
+            let Ok(id) = id.trim_start_matches('#').parse() else {
+                return Some(text_message(user_id, locale.schedule_usage().to_string()));
+            };
+            match state.db.remove_schedule(id).await {
+                Ok(true) => {
+                    info!("Audit: {} removed schedule #{}", requester, id);
+                    state.schedules_changed.notify_one();
+                    Some(text_message(user_id, locale.schedule_removed(id)))
+                }
+                Ok(false) => Some(text_message(user_id, locale.schedule_not_found(id))),
+                Err(e) => failed(e),
+            }
+        }
+        ScheduleUsage => Some(text_message(user_id, locale.schedule_usage().to_string())),
+        Reload => {
+            let message = match state.reload().await {
+                Ok(()) => locale.reloaded().to_string(),
+                Err(e) => {
+                    error!("Reloading: {e}");
+                    locale.reload_failed(&e)
+                }
+            };
+            Some(text_message(user_id, message))
+        }
+        SetLocale(code) => match Locale::from_code(&code) {
+            Some(new_locale) => match state.db.set_user_locale(user_id, new_locale.code()).await {
+                Ok(()) => Some(text_message(
+                    user_id,
+                    new_locale.locale_changed().to_string(),
+                )),
+                Err(e) => failed(e),
+            },
+            None => Some(text_message(user_id, locale.unknown_locale().to_string())),
+        },
+        Light {
+            callback_id,
+            action: GateAction::SpotlightFor(_, duration),
+        } if !bot_config
+            .spotlight_menu_minutes
+            .contains(&(duration.as_secs() / 60)) =>
+        {
+            warn!("{} asked for the light for {:?}", requester, duration);
+            Some(button_answer(
+                callback_id,
+                locale.not_permitted().to_string(),
+            ))
+        }
+        Light {
+            callback_id,
+            action,
+        } => {
+            let light = action.light()?;
+            let answer = match perform_gate_action(&state, &requester, action).await {
+                GateActionOutcome::UnknownDevice | GateActionOutcome::NotPermitted => {
+                    return Some(button_answer(
+                        callback_id,
+                        locale.not_permitted().to_string(),
+                    ))
+                }
+                GateActionOutcome::SpotlightAlreadyOn => locale.light_already_on(),
+                GateActionOutcome::SpotlightExtended => locale.light_extended(),
+                GateActionOutcome::SpotlightTurnedOff => locale.light_turned_off(),
+                _ => locale.light_turned_on(),
+            };
+            let answer = ButtonAnswer {
+                callback_query_id: callback_id,
+                message: answer.to_string(),
+            };
+            Some(light_controls_message(
+                &state,
+                user_id,
+                answer,
+                light,
+                &hardware.lights[light],
+                &bot_config,
+                locale,
+            ))
+        }
+        SetPin { current, new } => {
+            let new_hash = match new.0.as_str() {
+                "off" => None,
+                pin if pin.chars().count() >= 4 => Some(hash_pin_blocking(pin.to_string()).await),
+                _ => return Some(text_message(user_id, locale.pin_usage().to_string())),
+            };
+            let current = current.map(|current| current.0).unwrap_or_default();
+            match check_pin(&state, &requester, user_id, current, &bot_config, locale).await {
+                Ok(()) => {}
+                Err(PinError::Rejected(message)) => return Some(text_message(user_id, message)),
+                Err(PinError::Db(e)) => return failed(e),
+            }
+            match state.db.set_pin_hash(user_id, new_hash.as_deref()).await {
+                Ok(()) => {
+                    info!("Audit: {} changed their PIN", requester);
+                    let message = match new_hash {
+                        Some(_) => locale.pin_changed(),
+                        None => locale.pin_removed(),
+                    };
+                    Some(text_message(user_id, message.to_string()))
+                }
+                Err(e) => failed(e),
+            }
+        }
+        PinUsage => Some(text_message(user_id, locale.pin_usage().to_string())),
+        PrepareOpen { callback_id, gate } if !can_open(gate) => {
+            warn!("{} can't open gate {}", requester, gate);
+            Some(button_answer(
+                callback_id,
+                locale.not_permitted().to_string(),
+            ))
+        }
+        PrepareOpen { callback_id, gate } => {
+            let pin_state = match state.db.get_pin_state(user_id).await {
+                Ok(pin_state) => pin_state,
+                Err(e) => return failed(e),
+            };
+            let now = chrono::Utc::now().timestamp();
+            if pin_state.is_locked(now) {
+                Some(button_answer(
+                    callback_id,
+                    locale.pin_locked(lockout_minutes_left(&pin_state, now)),
+                ))
+            } else if pin_state.pin_hash.is_some() {
+                // The PIN typed next is for this gate only, whatever "Abrir" was pressed before.
+                state.pending_actions.remove_for_user(user_id, |action| {
+                    matches!(action, PendingAction::EnterPin { .. })
+                });
+                state.pending_actions.insert(
+                    user_id,
+                    PendingAction::EnterPin { gate },
+                    bot_config.pin_entry_window(),
+                );
+                Some(enter_pin_message(
+                    user_id,
+                    callback_id,
+                    &bot_config,
+                    locale,
+                    gate_name(gate),
+                ))
             } else {
-                state
-                    .hw
-                    .turn_on_spotlight(Duration::from_secs(60 * 3))
-                    .await;
-                Some(light_turned_on_response(callback_id))
-            }
-        }
-        PrepareOpen { callback_id } => {
-            state
-                .open_requests_waiting_confirmation
-                .write()
-                .await
-                .insert(user_id, std::time::Instant::now());
-            Some(prepare_open_message(user_id, callback_id))
-        }
-        ConfirmOpen { callback_id } => {
-            let read_guard = state.open_requests_waiting_confirmation.read().await;
-            if let Some(original_request_instant) = read_guard.get(&user_id) {
-                if original_request_instant.elapsed().as_secs() < 5 {
-                    state.hw.unlock_gate().await;
-                    return Some(gate_unlocked_message(callback_id));
+                Some(prepare_open_message(
+                    &state,
+                    user_id,
+                    Some(callback_id),
+                    &bot_config,
+                    locale,
+                    gate,
+                    gate_name(gate),
+                ))
+            }
+        }
+        Text(text) => {
+            let pin_asked_for = state.pending_actions.take_for_user(user_id, |action| {
+                matches!(action, PendingAction::EnterPin { .. })
+            });
+            let Some(Pending {
+                action: PendingAction::EnterPin { gate },
+                ..
+            }) = pin_asked_for
+            else {
+                return Some(default_response());
+            };
+            match check_pin(&state, &requester, user_id, text.0, &bot_config, locale).await {
+                Ok(()) => Some(prepare_open_message(
+                    &state,
+                    user_id,
+                    None,
+                    &bot_config,
+                    locale,
+                    gate,
+                    gate_name(gate),
+                )),
+                Err(PinError::Rejected(message)) => Some(text_message(user_id, message)),
+                Err(PinError::Db(e)) => failed(e),
+            }
+        }
+        ConfirmOpen {
+            callback_id,
+            payload,
+        } => {
+            let Some(payload) =
+                payload.filter(|payload| state.callback_signer.verify(payload, user_id))
+            else {
+                warn!(
+                    "Rejecting unsigned or forged confirmation from {}",
+                    requester
+                );
+                return Some(default_response());
+            };
+            match state.pending_actions.take(payload.nonce) {
+                Some(Pending {
+                    user_id: owner,
+                    action: PendingAction::OpenGate { gate },
+                    ..
+                }) if owner == user_id => {
+                    match night_approval_now(&bot_config, utc_offset_hours, authorized_user.role) {
+                        Some(night_approval) => Some(
+                            request_approval(
+                                &state,
+                                outbox,
+                                authorized_user,
+                                locale,
+                                night_approval,
+                                callback_id,
+                                gate,
+                            )
+                            .await,
+                        ),
+                        None => {
+                            match perform_gate_action(&state, &requester, GateAction::Open(gate))
+                                .await
+                            {
+                                GateActionOutcome::Unlocked => {
+                                    Some(gate_unlocked_message(callback_id, locale))
+                                }
+                                _ => Some(button_answer(
+                                    callback_id,
+                                    locale.not_permitted().to_string(),
+                                )),
+                            }
+                        }
+                    }
+                }
+                Some(pending) => {
+                    warn!(
+                        "Confirmation {:x} from {} doesn't match {:?} by {}",
+                        payload.nonce, requester, pending.action, pending.user_id
+                    );
+                    Some(default_response())
+                }
+                None => {
+                    warn!(
+                        "Expired, replayed or unknown confirmation {:x} from {}",
+                        payload.nonce, requester
+                    );
+                    Some(default_response())
                 }
             }
-            Some(default_message(user_id))
+        }
+        ReviewStranger { .. } if !is_admin => {
+            warn!("Non admin {} tried to review a stranger", requester);
+            Some(default_response())
+        }
+        ReviewStranger {
+            callback_id,
+            payload,
+            grant,
+        } => {
+            let Some(payload) =
+                payload.filter(|payload| state.callback_signer.verify(payload, user_id))
+            else {
+                warn!("Rejecting unsigned or forged review from {}", requester);
+                return Some(default_response());
+            };
+            match state.pending_actions.take(payload.nonce) {
+                Some(Pending {
+                    user_id: stranger_id,
+                    action:
+                        PendingAction::ReviewStranger {
+                            profile,
+                            locale: stranger_locale,
+                        },
+                    ..
+                }) => {
+                    let name = profile.full_name();
+                    if grant {
+                        let guest = match state.db.add_guest(stranger_id, &name).await {
+                            Ok(guest) => guest,
+                            Err(e) => return failed(e),
+                        };
+                        info!(
+                            "Audit: {} granted guest access to {} ({})",
+                            requester, name, stranger_id
+                        );
+                        let buttons = RequestedAction::default_buttons(
+                            &bot_config,
+                            &hardware,
+                            &guest,
+                            stranger_locale,
+                        );
+                        outbox.send(welcome_guest_message(stranger_id, buttons, stranger_locale));
+                        Some(button_answer(
+                            callback_id,
+                            locale.guest_access_granted(&name),
+                        ))
+                    } else {
+                        if let Err(e) = state.db.block_user(stranger_id).await {
+                            return failed(e);
+                        }
+                        info!("Audit: {} blocked {} ({})", requester, name, stranger_id);
+                        Some(button_answer(callback_id, locale.stranger_blocked(&name)))
+                    }
+                }
+                Some(pending) => {
+                    warn!(
+                        "Review {:x} from {} doesn't match {:?} by {}",
+                        payload.nonce, requester, pending.action, pending.user_id
+                    );
+                    Some(button_answer(
+                        callback_id,
+                        locale.approval_already_answered().to_string(),
+                    ))
+                }
+                None => Some(button_answer(
+                    callback_id,
+                    locale.approval_already_answered().to_string(),
+                )),
+            }
+        }
+        AnswerApproval {
+            callback_id,
+            payload,
+            approved,
+        } => {
+            let Some(payload) =
+                payload.filter(|payload| state.callback_signer.verify(payload, user_id))
+            else {
+                warn!("Rejecting unsigned or forged approval from {}", requester);
+                return Some(default_response());
+            };
+            if authorized_user.role == UserRole::Guest {
+                warn!("Guest {} tried to answer an approval", requester);
+                return Some(default_response());
+            }
+            match state.pending_actions.take(payload.nonce) {
+                Some(Pending {
+                    action:
+                        PendingAction::ApproveOpen {
+                            requester: opener,
+                            locale: opener_locale,
+                            gate,
+                        },
+                    ..
+                }) if opener.telegram_id != user_id => {
+                    let opener_id = opener.telegram_id;
+                    let notice = if approved {
+                        info!(
+                            "Audit: {} approved opening for {} ({})",
+                            requester, opener.name, opener_id
+                        );
+                        let opener = Requester::User {
+                            user: opener,
+                            via: "telegram",
+                        };
+                        match perform_gate_action(&state, &opener, GateAction::Open(gate)).await {
+                            GateActionOutcome::Unlocked => {
+                                opener_locale.approved_by(&authorized_user.name)
+                            }
+                            _ => opener_locale.approved_but_not_opened(&authorized_user.name),
+                        }
+                    } else {
+                        info!(
+                            "Audit: {} denied opening for {} ({})",
+                            requester, opener.name, opener_id
+                        );
+                        opener_locale.denied_by(&authorized_user.name)
+                    };
+                    outbox.send(text_message(opener_id, notice));
+                    Some(button_answer(
+                        callback_id,
+                        locale.approval_answered().to_string(),
+                    ))
+                }
+                Some(pending) => {
+                    warn!(
+                        "Approval {:x} from {} doesn't match {:?} by {}",
+                        payload.nonce, requester, pending.action, pending.user_id
+                    );
+                    Some(button_answer(
+                        callback_id,
+                        locale.approval_already_answered().to_string(),
+                    ))
+                }
+                None => Some(button_answer(
+                    callback_id,
+                    locale.approval_already_answered().to_string(),
+                )),
+            }
         }
     };
     response
 }
+
+#[derive(Debug, Error)]
+enum PinError {
+    /// Wrong or locked out, with what to tell the user.
+    #[error("PIN rejected: {0}")]
+    Rejected(String),
+    #[error(transparent)]
+    Db(#[from] DbError),
+}
+
+/// Checks the PIN of a user that has one, counting wrong ones towards the lockout.
+async fn check_pin<T: RawHardware>(
+    state: &State<T>,
+    requester: &Requester,
+    user_id: i64,
+    pin: String,
+    config: &BotConfig,
+    locale: Locale,
+) -> Result<(), PinError> {
+    let pin_state = state.db.get_pin_state(user_id).await?;
+    let now = chrono::Utc::now().timestamp();
+    if pin_state.is_locked(now) {
+        return Err(PinError::Rejected(
+            locale.pin_locked(lockout_minutes_left(&pin_state, now)),
+        ));
+    }
+    let Some(pin_hash) = pin_state.pin_hash else {
+        return Ok(());
+    };
+    let correct = tokio::task::spawn_blocking(move || verify_pin(&pin, &pin_hash))
+        .await
+        .expect("PIN verification panicked");
+    if correct {
+        if pin_state.failed_attempts > 0 {
+            if let Err(e) = state.db.reset_pin_failures(user_id).await {
+                error!("{}", e);
+            }
+        }
+        return Ok(());
+    }
+    let lock_until = now + config.pin_lockout_secs as i64;
+    let max_attempts = i32::try_from(config.pin_max_attempts).unwrap_or(i32::MAX);
+    let locked = state
+        .db
+        .record_pin_failure(user_id, max_attempts, lock_until)
+        .await?;
+    if locked {
+        warn!("Audit: locked PIN entry for {} after wrong PINs", requester);
+        Err(PinError::Rejected(
+            locale.pin_locked((config.pin_lockout_secs as i64 + 59) / 60),
+        ))
+    } else {
+        warn!("Wrong PIN from {}", requester);
+        Err(PinError::Rejected(locale.wrong_pin().to_string()))
+    }
+}
+
+async fn hash_pin_blocking(pin: String) -> String {
+    tokio::task::spawn_blocking(move || hash_pin(&pin))
+        .await
+        .expect("PIN hashing panicked")
+}
+
+fn lockout_minutes_left(pin_state: &PinState, now: i64) -> i64 {
+    (pin_state.locked_until.unwrap_or(now) - now + 59) / 60
+}
+
+/// Asks every other resident and admin to approve `requester` opening the gate. The first answer
+/// wins, and nobody answering within the timeout counts as a denial.
+async fn request_approval<T: RawHardware>(
+    state: &Arc<State<T>>,
+    outbox: &Outbox,
+    requester: DbUser,
+    locale: Locale,
+    night_approval: &NightApprovalConfig,
+    callback_query_id: String,
+    gate: usize,
+) -> TelegramResponse {
+    let approvers: Vec<DbUser> = state
+        .db
+        .users_with_roles(&[UserRole::Resident, UserRole::Admin])
+        .await
+        .into_iter()
+        .filter(|approver| approver.telegram_id != requester.telegram_id)
+        .collect();
+    if approvers.is_empty() {
+        warn!("Nobody can approve opening for {}", requester.name);
+        return button_answer(callback_query_id, locale.no_approvers().to_string());
+    }
+    let gate_name = state.config.read().await.hardware.gates[gate].name.clone();
+    info!(
+        "Audit: {} ({}) asked {} residents to approve opening {}",
+        requester.name,
+        requester.telegram_id,
+        approvers.len(),
+        gate_name
+    );
+    let timeout = night_approval.timeout();
+    let requester_id = requester.telegram_id;
+    let nonce = state.pending_actions.insert(
+        requester_id,
+        PendingAction::ApproveOpen {
+            requester: requester.clone(),
+            locale,
+            gate,
+        },
+        timeout,
+    );
+    let expires_at = chrono::Utc::now().timestamp() + night_approval.timeout_secs as i64;
+    for approver in approvers {
+        let approver_locale = Locale::choose(approver.locale.as_deref(), None);
+        let button = |label: &str, action| {
+            callback_button(
+                label.to_string(),
+                state.callback_signer.sign(
+                    CallbackPayload::new(action)
+                        .with_nonce(nonce)
+                        .expiring_at(expires_at),
+                    approver.telegram_id,
+                ),
+            )
+        };
+        let request = TelegramResponse::DeletableOutgoingMessage(DeletableOutgoingMessage {
+            outgoing_msg: OutgoingMessage {
+                user_id: approver.telegram_id,
+                message: approver_locale.approval_request(
+                    &requester.name,
+                    &gate_name,
+                    night_approval.timeout_secs,
+                ),
+                buttons: Some(
+                    [
+                        button(
+                            approver_locale.approve_button(),
+                            CallbackAction::ApproveOpen,
+                        ),
+                        button(approver_locale.deny_button(), CallbackAction::DenyOpen),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+                ),
+            },
+            button_answer: None,
+            delete_after: Some(timeout),
+        });
+        outbox.send(request);
+    }
+    let state = Arc::clone(state);
+    let outbox = outbox.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        if state.pending_actions.remove(nonce).is_some() {
+            info!("Audit: nobody approved opening for {}", requester.name);
+            outbox.send(text_message(
+                requester_id,
+                locale.approval_timed_out().to_string(),
+            ));
+        }
+    });
+    button_answer(callback_query_id, locale.waiting_for_approval().to_string())
+}
+
+/// Admins have this long to grant access to or block a stranger, who is only reported again after.
+const STRANGER_REVIEW_WINDOW: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Tells admins who tried to use the bot and what they sent, with buttons to grant them guest
+/// access or block them.
+async fn notify_admins_of_stranger<T: RawHardware>(
+    state: &State<T>,
+    outbox: &Outbox,
+    user_id: i64,
+    locale: Locale,
+    update: &TelegramUpdate,
+) {
+    let is_review = |action: &PendingAction| matches!(action, PendingAction::ReviewStranger { .. });
+    if state.pending_actions.contains_for_user(user_id, is_review) {
+        return;
+    }
+    let nonce = state.pending_actions.insert(
+        user_id,
+        PendingAction::ReviewStranger {
+            profile: update.sender.clone(),
+            locale,
+        },
+        STRANGER_REVIEW_WINDOW,
+    );
+    let expires_at = chrono::Utc::now().timestamp() + STRANGER_REVIEW_WINDOW.as_secs() as i64;
+    for admin in state.db.users_with_roles(&[UserRole::Admin]).await {
+        let admin_locale = Locale::choose(admin.locale.as_deref(), None);
+        let content = match &update.content {
+            Content::Message { text, .. } => text.chars().take(200).collect(),
+            Content::Button { .. } => admin_locale.stranger_pressed_button().to_string(),
+        };
+        let button = |label: &str, action| {
+            callback_button(
+                label.to_string(),
+                state.callback_signer.sign(
+                    CallbackPayload::new(action)
+                        .with_nonce(nonce)
+                        .expiring_at(expires_at),
+                    admin.telegram_id,
+                ),
+            )
+        };
+        let notification = TelegramResponse::DeletableOutgoingMessage(DeletableOutgoingMessage {
+            outgoing_msg: OutgoingMessage {
+                user_id: admin.telegram_id,
+                message: admin_locale.stranger_attempt(
+                    user_id,
+                    update.sender.username.as_deref(),
+                    &update.sender.full_name(),
+                    &content,
+                ),
+                buttons: Some(
+                    [
+                        button(
+                            admin_locale.grant_guest_button(),
+                            CallbackAction::GrantGuest,
+                        ),
+                        button(admin_locale.block_button(), CallbackAction::Block),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+                ),
+            },
+            button_answer: None,
+            delete_after: None,
+        });
+        outbox.send(notification);
+    }
+}
+
+async fn alert_admins_of_ban<T: RawHardware>(state: &State<T>, outbox: &Outbox, user_id: i64) {
+    let minutes = state.rate_limiter.ban_duration().as_secs() / 60;
+    for admin in state.db.users_with_roles(&[UserRole::Admin]).await {
+        let admin_locale = Locale::choose(admin.locale.as_deref(), None);
+        outbox.send(text_message(
+            admin.telegram_id,
+            admin_locale.unknown_user_banned(user_id, minutes),
+        ));
+    }
+}
+
 fn parse_user_request(update: TelegramUpdate) -> UserRequest {
-    let user_request = match update.content {
-        Content::Message(_msg) => Unclassified,
+    let (message_id, user_request) = match update.content {
+        Content::Message { text, message_id } => {
+            (Some(message_id), RequestedAction::from_message(&text))
+        }
         Content::Button {
-            text,
+            callback_data,
             callback_query_id,
-        } => RequestedAction::from_button_text(&text, callback_query_id),
+        } => (
+            None,
+            RequestedAction::from_callback_data(&callback_data, callback_query_id),
+        ),
     };
     UserRequest {
         user_id: update.user_id,
+        language_code: update.language_code,
+        message_id,
         action: user_request,
     }
 }
 
-fn prepare_open_message(user_id: i64, callback_query_id: String) -> TelegramResponse {
+/// The night approval openings by `role` need right now, if any.
+pub fn night_approval_now(
+    config: &BotConfig,
+    utc_offset_hours: i8,
+    role: UserRole,
+) -> Option<&NightApprovalConfig> {
+    let local_hour = (chrono::Utc::now() + chrono::Duration::hours(utc_offset_hours.into())).hour();
+    config
+        .night_approval
+        .as_ref()
+        .filter(|night| night.applies_to(role, local_hour))
+}
+
+/// Sends the "Confirmar Abrir" button, answering the "Abrir" button if the user pressed it just
+/// now instead of typing their PIN.
+fn prepare_open_message<T: RawHardware>(
+    state: &State<T>,
+    user_id: i64,
+    callback_query_id: Option<String>,
+    config: &BotConfig,
+    locale: Locale,
+    gate: usize,
+    gate_name: &str,
+) -> TelegramResponse {
+    let nonce = state.pending_actions.insert(
+        user_id,
+        PendingAction::OpenGate { gate },
+        config.confirm_window(),
+    );
+    let expires_at = chrono::Utc::now().timestamp() + config.confirm_window_secs as i64;
+    let confirm_payload = state.callback_signer.sign(
+        CallbackPayload::new(CallbackAction::ConfirmOpen)
+            .with_target(gate as i64)
+            .with_nonce(nonce)
+            .expiring_at(expires_at),
+        user_id,
+    );
+    TelegramResponse::DeletableOutgoingMessage(DeletableOutgoingMessage {
+        outgoing_msg: OutgoingMessage {
+            user_id,
+            message: locale.confirm_open_question(gate_name, config.confirm_window_secs),
+            buttons: Some(RequestedAction::confirm_open_button(
+                confirm_payload,
+                locale,
+            )),
+        },
+        button_answer: callback_query_id.map(|callback_query_id| ButtonAnswer {
+            callback_query_id,
+            message: locale.check_before_opening().to_string(),
+        }),
+        delete_after: Some(config.confirm_window()),
+    })
+}
+
+fn enter_pin_message(
+    user_id: i64,
+    callback_query_id: String,
+    config: &BotConfig,
+    locale: Locale,
+    gate_name: &str,
+) -> TelegramResponse {
     TelegramResponse::DeletableOutgoingMessage(DeletableOutgoingMessage {
         outgoing_msg: OutgoingMessage {
             user_id,
-            message: "Você realmente quer abrir? Essa messagem vai desaparecer em 5s".to_string(),
-            buttons: Some(RequestedAction::confirm_open_button()),
+            message: locale.enter_pin(gate_name, config.pin_entry_window_secs),
+            buttons: None,
         },
         button_answer: Some(ButtonAnswer {
             callback_query_id,
-            message: "Veja quem está na porta antes de abrir!".to_string(),
+            message: locale.check_before_opening().to_string(),
         }),
-        delete_after: Some(Duration::new(5, 0)),
+        delete_after: Some(config.pin_entry_window()),
     })
 }
 
-fn gate_unlocked_message(callback_query: String) -> TelegramResponse {
+fn gate_unlocked_message(callback_query: String, locale: Locale) -> TelegramResponse {
     TelegramResponse::ButtonAnswer(ButtonAnswer {
         callback_query_id: callback_query,
-        message: "Aberto!".to_string(),
+        message: locale.gate_unlocked().to_string(),
     })
 }
 
-fn light_turned_on_response(callback_query: String) -> TelegramResponse {
-    TelegramResponse::ButtonAnswer(ButtonAnswer {
-        callback_query_id: callback_query,
-        message: "Luz Ligada".to_string(),
+fn describe_schedule(schedule: &DbSchedule) -> String {
+    let mut description = format!(
+        "#{} {} {} {}",
+        schedule.id,
+        schedule.cron,
+        schedule.action.as_str(),
+        schedule.device
+    );
+    if let Some(minutes) = schedule.duration_minutes {
+        description.push_str(&format!(" {minutes}min"));
+    }
+    description
+}
+
+/// Parses `/schedule add CRON open GATE` or `/schedule add CRON light LIGHT MINUTES`, with the
+/// 5 field cron expression in local time.
+async fn add_schedule<T: RawHardware>(
+    state: &State<T>,
+    user_id: i64,
+    requester: &Requester,
+    words: &[String],
+    hardware: &HardwareConfig,
+    locale: Locale,
+) -> TelegramResponse {
+    let usage = || text_message(user_id, locale.schedule_usage().to_string());
+    if words.len() < 7 {
+        return usage();
+    }
+    let (cron, rest) = words.split_at(5);
+    let cron = cron.join(" ");
+    if let Err(e) = cron.parse::<CronSpec>() {
+        return text_message(user_id, locale.invalid_schedule(&e));
+    }
+    let device = rest[1].as_str();
+    let (action, duration_minutes) = match rest {
+        [action, _] if action == "open" && hardware.gates.iter().any(|gate| gate.id == device) => {
+            (ScheduledAction::Open, None)
+        }
+        [action, _, minutes]
+            if action == "light" && hardware.lights.iter().any(|light| light.id == device) =>
+        {
+            match minutes.parse() {
+                Ok(minutes @ 1..=720) => (ScheduledAction::Light, Some(minutes)),
+                _ => return usage(),
+            }
+        }
+        _ => return usage(),
+    };
+    match state
+        .db
+        .add_schedule(&cron, action, device, duration_minutes, user_id)
+        .await
+    {
+        Ok(id) => {
+            info!(
+                "Audit: {} added schedule #{} {} {} {}",
+                requester,
+                id,
+                cron,
+                action.as_str(),
+                device
+            );
+            state.schedules_changed.notify_one();
+            text_message(user_id, locale.schedule_added(id))
+        }
+        Err(e) => database_failure(e, user_id, None, locale),
+    }
+}
+
+/// The time the light stays on, with buttons to extend it, turn it off or pick another duration.
+/// Deleted when the light turns off, and only the answer is sent when it is already off.
+fn light_controls_message<T: RawHardware>(
+    state: &State<T>,
+    user_id: i64,
+    answer: ButtonAnswer,
+    light: usize,
+    light_config: &LightConfig,
+    config: &BotConfig,
+    locale: Locale,
+) -> TelegramResponse {
+    let Some(remaining) = state.hw.spotlight_remaining(light) else {
+        return TelegramResponse::ButtonAnswer(answer);
+    };
+    let button = |label: String, action, minutes: u64| {
+        callback_button(
+            label,
+            CallbackPayload::new(action)
+                .with_target(light as i64)
+                .with_amount(minutes as u32),
+        )
+    };
+    let mut buttons: Vec<Button> = [
+        button(
+            locale.extend_light_button(light_config.duration(config).as_secs() / 60),
+            CallbackAction::ExtendLight,
+            0,
+        ),
+        button(
+            locale.turn_off_light_button().to_string(),
+            CallbackAction::LightOff,
+            0,
+        ),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    buttons.extend(config.spotlight_menu_minutes.iter().filter_map(|&minutes| {
+        button(
+            locale.light_minutes_button(minutes),
+            CallbackAction::LightFor,
+            minutes,
+        )
+    }));
+    TelegramResponse::DeletableOutgoingMessage(DeletableOutgoingMessage {
+        outgoing_msg: OutgoingMessage {
+            user_id,
+            message: locale.light_remaining(&light_config.name, remaining),
+            buttons: Some(buttons),
+        },
+        button_answer: Some(answer),
+        delete_after: Some(remaining),
+    })
+}
+
+fn default_message(user_id: i64, buttons: Vec<Button>, locale: Locale) -> TelegramResponse {
+    TelegramResponse::DeletableOutgoingMessage(DeletableOutgoingMessage {
+        outgoing_msg: OutgoingMessage {
+            user_id,
+            message: locale.check_before_opening().to_string(),
+            buttons: Some(buttons),
+        },
+        delete_after: None,
+        button_answer: None,
+    })
+}
+
+fn welcome_guest_message(user_id: i64, buttons: Vec<Button>, locale: Locale) -> TelegramResponse {
+    TelegramResponse::DeletableOutgoingMessage(DeletableOutgoingMessage {
+        outgoing_msg: OutgoingMessage {
+            user_id,
+            message: locale.welcome_guest().to_string(),
+            buttons: Some(buttons),
+        },
+        delete_after: None,
+        button_answer: None,
     })
 }
 
-fn light_already_on_response(callback_query: String) -> TelegramResponse {
+/// Tells the user their request failed instead of leaving them waiting for an answer.
+fn database_failure(
+    e: DbError,
+    user_id: i64,
+    callback_query: Option<String>,
+    locale: Locale,
+) -> TelegramResponse {
+    error!("{}", e);
+    let message = match e {
+        DbError::Unavailable(_) => locale.database_unavailable(),
+        DbError::UserNotFound(_) | DbError::Query(_) => locale.request_failed(),
+    };
+    match callback_query {
+        Some(callback_query) => button_answer(callback_query, message.to_string()),
+        None => text_message(user_id, message.to_string()),
+    }
+}
+
+/// `None`, after logging it, when the payload doesn't fit in a button.
+fn callback_button(label: String, payload: CallbackPayload) -> Option<Button> {
+    match payload.encode() {
+        Ok(callback_data) => Some(Button {
+            label,
+            callback_data,
+        }),
+        Err(e) => {
+            error!("Leaving out button {label:?}: {e}");
+            None
+        }
+    }
+}
+
+fn button_answer(callback_query: String, message: String) -> TelegramResponse {
     TelegramResponse::ButtonAnswer(ButtonAnswer {
         callback_query_id: callback_query,
-        message: "Já está ligada".to_string(),
+        message,
     })
 }
 
-fn default_message(user_id: i64) -> TelegramResponse {
+fn text_message(user_id: i64, message: String) -> TelegramResponse {
     TelegramResponse::DeletableOutgoingMessage(DeletableOutgoingMessage {
         outgoing_msg: OutgoingMessage {
             user_id,
-            message: "Veja quem está na porta antes de abrir!".to_string(),
-            buttons: Some(RequestedAction::default_buttons()),
+            message,
+            buttons: None,
         },
         delete_after: None,
         button_answer: None,
     })
 }
 
-fn unauthorized(user_id: i64) -> TelegramResponse {
+fn unauthorized(user_id: i64, locale: Locale) -> TelegramResponse {
     TelegramResponse::DeletableOutgoingMessage(DeletableOutgoingMessage {
         outgoing_msg: OutgoingMessage {
             user_id,
-            message: format!("Você não está cadastrado. Envie essa mensagem para @TiberioFerreira com seu id: {}", user_id),
+            message: locale.unauthorized(user_id),
             buttons: None,
         },
         button_answer: None,
-        delete_after: None
+        delete_after: None,
     })
 }
+
+#[cfg(test)]
+mod tests;
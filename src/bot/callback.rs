@@ -0,0 +1,378 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Bumped whenever the encoding changes, so buttons in old messages are rejected instead of
+/// being misinterpreted.
+const VERSION: &str = "2";
+/// Telegram rejects buttons with more callback data than this.
+const MAX_CALLBACK_DATA_BYTES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallbackAction {
+    Light,
+    ExtendLight,
+    LightOff,
+    /// Keeps the light on for `amount` minutes.
+    LightFor,
+    PrepareOpen,
+    ConfirmOpen,
+    ApproveOpen,
+    DenyOpen,
+    GrantGuest,
+    Block,
+}
+
+impl CallbackAction {
+    fn code(self) -> &'static str {
+        match self {
+            CallbackAction::Light => "l",
+            CallbackAction::ExtendLight => "x",
+            CallbackAction::LightOff => "f",
+            CallbackAction::LightFor => "m",
+            CallbackAction::PrepareOpen => "o",
+            CallbackAction::ConfirmOpen => "c",
+            CallbackAction::ApproveOpen => "a",
+            CallbackAction::DenyOpen => "d",
+            CallbackAction::GrantGuest => "g",
+            CallbackAction::Block => "b",
+        }
+    }
+}
+
+impl FromStr for CallbackAction {
+    type Err = CallbackError;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        match code {
+            "l" => Ok(CallbackAction::Light),
+            "x" => Ok(CallbackAction::ExtendLight),
+            "f" => Ok(CallbackAction::LightOff),
+            "m" => Ok(CallbackAction::LightFor),
+            "o" => Ok(CallbackAction::PrepareOpen),
+            "c" => Ok(CallbackAction::ConfirmOpen),
+            "a" => Ok(CallbackAction::ApproveOpen),
+            "d" => Ok(CallbackAction::DenyOpen),
+            "g" => Ok(CallbackAction::GrantGuest),
+            "b" => Ok(CallbackAction::Block),
+            other => Err(CallbackError::UnknownAction(other.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CallbackError {
+    #[error("callback data {0:?} is not in the versioned format")]
+    Malformed(String),
+    #[error("unsupported callback data version {0}")]
+    UnsupportedVersion(String),
+    #[error("unknown callback action {0}")]
+    UnknownAction(String),
+    #[error("callback expired at {expired_at}")]
+    Expired { expired_at: i64 },
+    #[error("callback data {0} is longer than Telegram accepts")]
+    TooLong(String),
+}
+
+/// What a button does, encoded as its callback data:
+/// `version:action:target:amount:nonce:expiry`, optionally followed by `:signature`.
+/// `target` identifies what the action applies to (a gate, a user...), `amount` is a quantity
+/// for it (minutes...), `nonce` ties the button to a single server side request and `expiry` is a
+/// unix timestamp, all of them `0` when unused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallbackPayload {
+    pub action: CallbackAction,
+    pub target: i64,
+    pub amount: u32,
+    pub nonce: u64,
+    pub expires_at: Option<i64>,
+    /// See [CallbackSigner].
+    pub signature: Option<u64>,
+}
+
+impl CallbackPayload {
+    pub fn new(action: CallbackAction) -> Self {
+        Self {
+            action,
+            target: 0,
+            amount: 0,
+            nonce: 0,
+            expires_at: None,
+            signature: None,
+        }
+    }
+
+    pub fn with_target(self, target: i64) -> Self {
+        Self { target, ..self }
+    }
+
+    pub fn with_amount(self, amount: u32) -> Self {
+        Self { amount, ..self }
+    }
+
+    pub fn with_nonce(self, nonce: u64) -> Self {
+        Self { nonce, ..self }
+    }
+
+    pub fn expiring_at(self, unix_timestamp: i64) -> Self {
+        Self {
+            expires_at: Some(unix_timestamp),
+            ..self
+        }
+    }
+
+    /// Fails when a signature together with large fields doesn't fit in a button.
+    pub fn encode(&self) -> Result<String, CallbackError> {
+        let encoded = self.to_string();
+        if encoded.len() > MAX_CALLBACK_DATA_BYTES {
+            return Err(CallbackError::TooLong(encoded));
+        }
+        Ok(encoded)
+    }
+
+    /// Parses and validates the callback data, rejecting it if it expired before `now`.
+    pub fn parse(callback_data: &str, now: i64) -> Result<CallbackPayload, CallbackError> {
+        let malformed = || CallbackError::Malformed(callback_data.to_string());
+        let (version, fields) = callback_data.split_once(':').ok_or_else(malformed)?;
+        if version != VERSION {
+            return Err(CallbackError::UnsupportedVersion(version.to_string()));
+        }
+        let fields: Vec<&str> = fields.split(':').collect();
+        let (action, target, amount, nonce, expiry, signature) = match fields[..] {
+            [action, target, amount, nonce, expiry] => {
+                (action, target, amount, nonce, expiry, None)
+            }
+            [action, target, amount, nonce, expiry, signature] => {
+                (action, target, amount, nonce, expiry, Some(signature))
+            }
+            _ => return Err(malformed()),
+        };
+        let payload = CallbackPayload {
+            action: action.parse()?,
+            target: target.parse().map_err(|_| malformed())?,
+            amount: amount.parse().map_err(|_| malformed())?,
+            nonce: u64::from_str_radix(nonce, 16).map_err(|_| malformed())?,
+            expires_at: match expiry.parse().map_err(|_| malformed())? {
+                0 => None,
+                expires_at => Some(expires_at),
+            },
+            signature: signature
+                .map(|signature| u64::from_str_radix(signature, 16))
+                .transpose()
+                .map_err(|_| malformed())?,
+        };
+        match payload.expires_at {
+            Some(expired_at) if expired_at < now => Err(CallbackError::Expired { expired_at }),
+            _ => Ok(payload),
+        }
+    }
+}
+
+impl Display for CallbackPayload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{VERSION}:{}:{}:{}:{:x}:{}",
+            self.action.code(),
+            self.target,
+            self.amount,
+            self.nonce,
+            self.expires_at.unwrap_or(0)
+        )?;
+        if let Some(signature) = self.signature {
+            write!(f, ":{signature:x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Signs payloads with a key generated on startup, binding them to the user they were sent to,
+/// so buttons can't be forged or forwarded. The signature is a HMAC-SHA256 truncated to 64 bits
+/// to fit Telegram's callback data limit.
+pub struct CallbackSigner {
+    key: [u8; 32],
+}
+
+impl CallbackSigner {
+    pub fn random() -> Self {
+        Self {
+            key: rand::random(),
+        }
+    }
+
+    fn mac(&self, payload: &CallbackPayload, user_id: i64) -> Hmac<Sha256> {
+        let unsigned = CallbackPayload {
+            signature: None,
+            ..*payload
+        };
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(unsigned.to_string().as_bytes());
+        mac.update(&user_id.to_be_bytes());
+        mac
+    }
+
+    pub fn sign(&self, payload: CallbackPayload, user_id: i64) -> CallbackPayload {
+        let tag = self.mac(&payload, user_id).finalize().into_bytes();
+        let signature = u64::from_be_bytes(tag[..8].try_into().expect("tag has 32 bytes"));
+        CallbackPayload {
+            signature: Some(signature),
+            ..payload
+        }
+    }
+
+    pub fn verify(&self, payload: &CallbackPayload, user_id: i64) -> bool {
+        match payload.signature {
+            Some(signature) => self
+                .mac(payload, user_id)
+                .verify_truncated_left(&signature.to_be_bytes())
+                .is_ok(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn full_payload() -> CallbackPayload {
+        CallbackPayload::new(CallbackAction::LightFor)
+            .with_target(-3)
+            .with_amount(30)
+            .with_nonce(0xbeef)
+            .expiring_at(NOW + 60)
+    }
+
+    #[test]
+    fn parses_what_it_displays() {
+        let payload = full_payload();
+        let encoded = payload.encode().unwrap();
+        assert_eq!(encoded, format!("2:m:-3:30:beef:{}", NOW + 60));
+        assert_eq!(CallbackPayload::parse(&encoded, NOW), Ok(payload));
+
+        let signed = CallbackPayload {
+            signature: Some(u64::MAX),
+            ..payload
+        };
+        let encoded = signed.encode().unwrap();
+        assert!(encoded.ends_with(":ffffffffffffffff"));
+        assert_eq!(CallbackPayload::parse(&encoded, NOW), Ok(signed));
+
+        let unused = CallbackPayload::new(CallbackAction::Block);
+        assert_eq!(unused.to_string(), "2:b:0:0:0:0");
+        assert_eq!(CallbackPayload::parse("2:b:0:0:0:0", NOW), Ok(unused));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        assert_eq!(
+            CallbackPayload::parse("1:m:0:30:0:0", NOW),
+            Err(CallbackError::UnsupportedVersion("1".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_actions() {
+        assert_eq!(
+            CallbackPayload::parse("2:z:0:0:0:0", NOW),
+            Err(CallbackError::UnknownAction("z".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_fields() {
+        for callback_data in [
+            "Abrir",
+            "2:m:0:30:0",
+            "2:m:0:30:0:0:0:0",
+            "2:m:x:30:0:0",
+            "2:m:0:-1:0:0",
+            "2:m:0:30:zz:0",
+            "2:m:0:30:0:soon",
+            "2:m:0:30:0:0:not-hex",
+        ] {
+            assert_eq!(
+                CallbackPayload::parse(callback_data, NOW),
+                Err(CallbackError::Malformed(callback_data.to_string())),
+                "{callback_data}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_expired_payloads() {
+        let encoded = full_payload().encode().unwrap();
+        assert!(CallbackPayload::parse(&encoded, NOW + 60).is_ok());
+        assert_eq!(
+            CallbackPayload::parse(&encoded, NOW + 61),
+            Err(CallbackError::Expired {
+                expired_at: NOW + 60
+            })
+        );
+    }
+
+    #[test]
+    fn refuses_to_encode_too_much_for_a_button() {
+        let payload = CallbackPayload {
+            target: i64::MIN,
+            amount: u32::MAX,
+            nonce: u64::MAX,
+            signature: Some(u64::MAX),
+            ..full_payload()
+        };
+        assert!(matches!(payload.encode(), Err(CallbackError::TooLong(_))));
+    }
+
+    const USER: i64 = 42;
+
+    #[test]
+    fn signed_payloads_verify_after_a_round_trip() {
+        let signer = CallbackSigner::random();
+        let signed = signer.sign(full_payload(), USER);
+        let parsed = CallbackPayload::parse(&signed.encode().unwrap(), NOW).unwrap();
+        assert!(signer.verify(&parsed, USER));
+    }
+
+    #[test]
+    fn tampered_fields_are_rejected() {
+        let signer = CallbackSigner::random();
+        let signed = signer.sign(full_payload(), USER);
+        let tampered = [
+            CallbackPayload {
+                action: CallbackAction::Light,
+                ..signed
+            },
+            signed.with_target(4),
+            signed.with_amount(720),
+            signed.with_nonce(0xbeee),
+            signed.expiring_at(NOW + 3600),
+        ];
+        for payload in tampered {
+            assert!(!signer.verify(&payload, USER), "{payload}");
+        }
+    }
+
+    #[test]
+    fn buttons_signed_for_someone_else_are_rejected() {
+        let signer = CallbackSigner::random();
+        let signed = signer.sign(full_payload(), USER);
+        assert!(!signer.verify(&signed, USER + 1));
+    }
+
+    #[test]
+    fn unsigned_payloads_are_rejected() {
+        let signer = CallbackSigner::random();
+        assert!(!signer.verify(&full_payload(), USER));
+    }
+
+    #[test]
+    fn buttons_from_before_a_restart_are_rejected() {
+        let signed = CallbackSigner::random().sign(full_payload(), USER);
+        assert!(!CallbackSigner::random().verify(&signed, USER));
+    }
+}
@@ -0,0 +1,385 @@
+use crate::database::UserRole;
+use crate::hardware::simulator::Output;
+use crate::harness::{self, Harness, ADMIN, GUEST, RESIDENT, STRANGER};
+use crate::i18n::Locale;
+use crate::telegram::OutgoingMessage;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const LOCALE: Locale = Locale::PtBr;
+
+fn pulses(harness: &Harness) -> usize {
+    harness
+        .hardware
+        .transitions(Output::Gate(0))
+        .iter()
+        .filter(|transition| transition.high)
+        .count()
+}
+
+/// Gets the default buttons and presses "Abrir", returning the callback data of "Confirmar Abrir".
+async fn prepare_open(harness: &Harness, user_id: i64) -> String {
+    harness.send_text(user_id, "oi").await;
+    let answer = harness.press_button(user_id, LOCALE.open_button()).await;
+    assert_eq!(harness.answer_to(&answer), LOCALE.check_before_opening());
+    let confirm = harness.last_message_to(user_id);
+    assert_eq!(confirm.message, LOCALE.confirm_open_question("Portão", 5));
+    confirm.buttons.expect("confirm button")[0]
+        .callback_data
+        .clone()
+}
+
+#[tokio::test(start_paused = true)]
+async fn prepare_confirm_unlock() {
+    let harness = Harness::start().await;
+    harness.send_text(RESIDENT, "oi").await;
+    let menu = harness.last_message_to(RESIDENT);
+    let labels: Vec<String> = menu
+        .buttons
+        .expect("default buttons")
+        .into_iter()
+        .map(|button| button.label)
+        .collect();
+    assert_eq!(labels, vec!["Abrir", "Luz 3min"]);
+
+    let start = Instant::now();
+    harness.press_button(RESIDENT, "Abrir").await;
+    let (confirm_id, _) = *harness
+        .telegram
+        .messages_to(RESIDENT)
+        .last()
+        .expect("confirm message");
+    let answer = harness.press_button(RESIDENT, "Confirmar Abrir").await;
+    assert_eq!(harness.answer_to(&answer), LOCALE.gate_unlocked());
+    assert_eq!(pulses(&harness), 1);
+
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    let deleted_at = harness
+        .telegram
+        .deleted_at(RESIDENT, confirm_id)
+        .expect("confirm message deleted");
+    assert!(deleted_at - start >= Duration::from_secs(5));
+    assert!(deleted_at - start < Duration::from_secs(6));
+}
+
+#[tokio::test(start_paused = true)]
+async fn confirmation_expires() {
+    let harness = Harness::start().await;
+    let confirm = prepare_open(&harness, RESIDENT).await;
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    harness.press(RESIDENT, &confirm).await;
+    assert_eq!(pulses(&harness), 0);
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.check_before_opening()
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn confirmation_works_once() {
+    let harness = Harness::start().await;
+    let confirm = prepare_open(&harness, RESIDENT).await;
+    harness.press(RESIDENT, &confirm).await;
+    harness.press(RESIDENT, &confirm).await;
+    assert_eq!(pulses(&harness), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn confirmation_only_works_for_its_user() {
+    let harness = Harness::start().await;
+    let confirm = prepare_open(&harness, RESIDENT).await;
+    harness.press(GUEST, &confirm).await;
+    assert_eq!(pulses(&harness), 0);
+    harness.press(RESIDENT, &confirm).await;
+    assert_eq!(pulses(&harness), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn pin_is_asked_before_confirming() {
+    let harness = Harness::start().await;
+    harness.send_text(RESIDENT, "/pin 4321").await;
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.pin_changed()
+    );
+    harness.send_text(RESIDENT, "oi").await;
+    harness.press_button(RESIDENT, "Abrir").await;
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.enter_pin("Portão", 30)
+    );
+    harness.send_text(RESIDENT, "4321").await;
+    harness.press_button(RESIDENT, "Confirmar Abrir").await;
+    assert_eq!(pulses(&harness), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn pin_messages_are_deleted() {
+    let harness = Harness::start().await;
+    let set_pin = harness.send_text(RESIDENT, "/pin 4321").await;
+    let change_pin = harness.send_text(RESIDENT, "/pin 4321 8765").await;
+    let greeting = harness.send_text(RESIDENT, "oi").await;
+    harness.press_button(RESIDENT, "Abrir").await;
+    let pin = harness.send_text(RESIDENT, "8765").await;
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.confirm_open_question("Portão", 5)
+    );
+    for message_id in [set_pin, change_pin, pin] {
+        assert!(harness.telegram.deleted_at(RESIDENT, message_id).is_some());
+    }
+    assert!(harness.telegram.deleted_at(RESIDENT, greeting).is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn pin_opens_the_gate_asked_for_last() {
+    let mut config = harness::config();
+    let mut garage = config.hardware.gates[0].clone();
+    garage.id = "garage".to_string();
+    garage.name = "Garagem".to_string();
+    garage.pin = 27;
+    config.hardware.gates.push(garage);
+    config.rate_limit.gate_actions_burst = 10;
+    let harness = Harness::with_config(config).await;
+    harness.send_text(RESIDENT, "/pin 4321").await;
+    harness.send_text(RESIDENT, "oi").await;
+    let menu = harness.last_message_to(RESIDENT).buttons.expect("menu");
+    harness.press(RESIDENT, &menu[0].callback_data).await;
+    harness.press(RESIDENT, &menu[1].callback_data).await;
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.enter_pin("Garagem", 30)
+    );
+    harness.send_text(RESIDENT, "4321").await;
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.confirm_open_question("Garagem", 5)
+    );
+    let answer = harness.press_button(RESIDENT, "Confirmar Abrir").await;
+    assert_eq!(harness.answer_to(&answer), LOCALE.gate_unlocked());
+    assert_eq!(pulses(&harness), 0);
+    assert_eq!(harness.hardware.transitions(Output::Gate(1)).len(), 2);
+    // the PIN asked for the first gate went away with it
+    harness.send_text(RESIDENT, "4321").await;
+    assert_ne!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.confirm_open_question("Portão", 5)
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn light_turns_off_after_its_duration() {
+    let harness = Harness::start().await;
+    harness.send_text(GUEST, "oi").await;
+    let answer = harness.press_button(GUEST, "Luz 3min").await;
+    assert_eq!(harness.answer_to(&answer), LOCALE.light_turned_on());
+    assert!(harness.hardware.is_high(Output::Light(0)));
+    tokio::time::sleep(Duration::from_secs(3 * 60)).await;
+    assert!(!harness.hardware.is_high(Output::Light(0)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn admin_grants_a_stranger_guest_access() {
+    let harness = Harness::start().await;
+    harness.send_text(STRANGER, "oi").await;
+    assert_eq!(
+        harness.last_message_to(STRANGER).message,
+        LOCALE.unauthorized(STRANGER)
+    );
+    let answer = harness
+        .press_button(ADMIN, LOCALE.grant_guest_button())
+        .await;
+    assert_eq!(
+        harness.answer_to(&answer),
+        LOCALE.guest_access_granted(&format!("User {STRANGER}"))
+    );
+    assert_eq!(
+        harness.last_message_to(STRANGER).message,
+        LOCALE.welcome_guest()
+    );
+    let guest = harness.state.db.get_user(STRANGER).await.unwrap();
+    assert_eq!(guest.map(|guest| guest.role), Some(UserRole::Guest));
+    prepare_open(&harness, STRANGER).await;
+    harness.press_button(STRANGER, "Confirmar Abrir").await;
+    assert_eq!(pulses(&harness), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn admins_manage_schedules() {
+    let harness = Harness::start().await;
+    harness
+        .send_text(ADMIN, "/schedule add 30 7 * * 1-5 open gate")
+        .await;
+    assert_eq!(
+        harness.last_message_to(ADMIN).message,
+        LOCALE.schedule_added(1)
+    );
+    harness
+        .send_text(ADMIN, "/schedule add 0 19 * * * light spotlight 30")
+        .await;
+    harness.send_text(ADMIN, "/schedule list").await;
+    assert_eq!(
+        harness.last_message_to(ADMIN).message,
+        "#1 30 7 * * 1-5 open gate\n#2 0 19 * * * light spotlight 30min"
+    );
+    harness.send_text(ADMIN, "/schedule remove #1").await;
+    assert_eq!(
+        harness.last_message_to(ADMIN).message,
+        LOCALE.schedule_removed(1)
+    );
+    let schedules = harness.state.db.schedules().await.unwrap();
+    assert_eq!(schedules.len(), 1);
+    assert_eq!(schedules[0].created_by, ADMIN);
+}
+
+/// With the clock paused the sleeps end right away, so the wall clock is still before the run when
+/// the scheduler wakes up, like it can be when the two drift apart.
+#[tokio::test(start_paused = true)]
+async fn schedules_run_once_even_if_the_wall_clock_lags() {
+    let harness = Harness::start().await;
+    tokio::spawn(crate::automation::run_schedules(Arc::clone(&harness.state)));
+    harness
+        .send_text(ADMIN, "/schedule add * * * * * open gate")
+        .await;
+    tokio::time::sleep(Duration::from_secs(61)).await;
+    assert_eq!(pulses(&harness), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn residents_cant_manage_schedules() {
+    let harness = Harness::start().await;
+    harness
+        .send_text(RESIDENT, "/schedule add 30 7 * * * open gate")
+        .await;
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.check_before_opening()
+    );
+    assert!(harness.state.db.schedules().await.unwrap().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn users_are_told_when_the_database_is_down() {
+    let harness = Harness::start().await;
+    harness.send_text(RESIDENT, "oi").await;
+    harness.store.set_unavailable(true);
+    let answer = harness.press_button(RESIDENT, "Abrir").await;
+    assert_eq!(harness.answer_to(&answer), LOCALE.database_unavailable());
+    harness.send_text(ADMIN, "/schedule list").await;
+    assert_eq!(
+        harness.last_message_to(ADMIN).message,
+        LOCALE.database_unavailable()
+    );
+    harness.send_text(STRANGER, "oi").await;
+    assert_eq!(
+        harness.last_message_to(STRANGER).message,
+        LOCALE.database_unavailable()
+    );
+
+    harness.store.set_unavailable(false);
+    prepare_open(&harness, RESIDENT).await;
+    harness.press_button(RESIDENT, "Confirmar Abrir").await;
+    assert_eq!(pulses(&harness), 1);
+}
+
+/// Residents need someone else to approve opening, whenever the test runs.
+async fn start_with_approvals() -> Harness {
+    Harness::with_config(harness::config_with_night_approval()).await
+}
+
+/// Asks to open as the resident, returning the admin's approval request.
+async fn ask_for_approval(harness: &Harness) -> OutgoingMessage {
+    prepare_open(harness, RESIDENT).await;
+    let answer = harness.press_button(RESIDENT, "Confirmar Abrir").await;
+    assert_eq!(harness.answer_to(&answer), LOCALE.waiting_for_approval());
+    harness.last_message_to(ADMIN)
+}
+
+fn approve_button(request: &OutgoingMessage) -> String {
+    request
+        .buttons
+        .iter()
+        .flatten()
+        .find(|button| button.label == LOCALE.approve_button())
+        .expect("approve button")
+        .callback_data
+        .clone()
+}
+
+#[tokio::test(start_paused = true)]
+async fn approved_opening_unlocks() {
+    let harness = start_with_approvals().await;
+    ask_for_approval(&harness).await;
+    assert_eq!(pulses(&harness), 0);
+    harness.press_button(ADMIN, LOCALE.approve_button()).await;
+    assert_eq!(pulses(&harness), 1);
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.approved_by("Admin")
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn approval_of_a_removed_gate_says_it_did_not_open() {
+    let harness = start_with_approvals().await;
+    ask_for_approval(&harness).await;
+    harness.state.config.write().await.hardware.gates.clear();
+    harness.press_button(ADMIN, LOCALE.approve_button()).await;
+    assert_eq!(pulses(&harness), 0);
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.approved_but_not_opened("Admin")
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn removed_users_lose_access_once_their_cache_entry_expires() {
+    let harness = Harness::start().await;
+    prepare_open(&harness, RESIDENT).await;
+    harness.store.remove_user(RESIDENT);
+    tokio::time::sleep(Duration::from_secs(61)).await;
+    harness.send_text(RESIDENT, "oi").await;
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.unauthorized(RESIDENT)
+    );
+    assert!(!harness.state.db.is_cached(RESIDENT).await);
+}
+
+#[tokio::test(start_paused = true)]
+async fn denied_opening_stays_closed() {
+    let harness = start_with_approvals().await;
+    let request = ask_for_approval(&harness).await;
+    let answer = harness.press_button(ADMIN, LOCALE.deny_button()).await;
+    assert_eq!(harness.answer_to(&answer), LOCALE.approval_answered());
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.denied_by("Admin")
+    );
+
+    let answer = harness.press(ADMIN, &approve_button(&request)).await;
+    assert_eq!(
+        harness.answer_to(&answer),
+        LOCALE.approval_already_answered()
+    );
+    assert_eq!(pulses(&harness), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn unanswered_approval_times_out() {
+    let harness = start_with_approvals().await;
+    let request = ask_for_approval(&harness).await;
+    tokio::time::sleep(Duration::from_secs(61)).await;
+    assert_eq!(
+        harness.last_message_to(RESIDENT).message,
+        LOCALE.approval_timed_out()
+    );
+
+    let answer = harness.press(ADMIN, &approve_button(&request)).await;
+    assert_eq!(
+        harness.answer_to(&answer),
+        LOCALE.approval_already_answered()
+    );
+    assert_eq!(pulses(&harness), 0);
+}
@@ -0,0 +1,256 @@
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Days searched ahead for the next run, enough to reach the next 29th of February.
+const MAX_DAYS_AHEAD: i64 = 366 * 4;
+
+/// Bits 1 to 31.
+const EVERY_DAY_OF_MONTH: u64 = ((1 << 32) - 1) & !1;
+/// Bits 0 to 6, Sunday as 7 is folded into 0.
+const EVERY_DAY_OF_WEEK: u64 = (1 << 7) - 1;
+
+/// A cron expression: `minute hour day-of-month month day-of-week`, each `*`, a number, a range
+/// `a-b`, a step `*/n` or `a-b/n`, or a list of those separated by commas. Days of the week go
+/// from 0 (Sunday) to 6, 7 is also Sunday. As in cron, when both days of the month and of the
+/// week are restricted either of them matching is enough. A field is restricted when it leaves
+/// out some day, however it is written, so `1-31` is as good as `*` but `*/2` restricts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSpec {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl CronSpec {
+    /// The first minute strictly after `after` that matches.
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        for days in 0..MAX_DAYS_AHEAD {
+            let date = start.date() + chrono::Duration::days(days);
+            if !self.matches_day(date) {
+                continue;
+            }
+            for hour in 0..24 {
+                if !is_set(self.hours, hour) {
+                    continue;
+                }
+                for minute in 0..60 {
+                    if !is_set(self.minutes, minute) {
+                        continue;
+                    }
+                    let candidate = date.and_hms_opt(hour, minute, 0)?;
+                    if candidate >= start {
+                        return Some(candidate);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, date: chrono::NaiveDate) -> bool {
+        if !is_set(self.months, date.month()) {
+            return false;
+        }
+        let day_of_month = is_set(self.days_of_month, date.day());
+        let day_of_week = is_set(self.days_of_week, date.weekday().num_days_from_sunday());
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+}
+
+fn is_set(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+/// Parses one field into a bit per allowed value.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .map_err(|_| format!("invalid step in {part}"))?;
+                if step == 0 {
+                    return Err(format!("invalid step in {part}"));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let number = |value: &str| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .ok_or_else(|| format!("{value} is not between {min} and {max}"))
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((first, last)) => (number(first)?, number(last)?),
+                None if step > 1 => (number(range)?, max),
+                None => {
+                    let value = number(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if first > last {
+            return Err(format!("invalid range {range}"));
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for CronSpec {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day_of_month, month, day_of_week] = fields[..] else {
+            return Err(format!(
+                "{expression:?} needs 5 fields: minute hour day-of-month month day-of-week"
+            ));
+        };
+        let mut days_of_week = parse_field(day_of_week, 0, 7)?;
+        if is_set(days_of_week, 7) {
+            days_of_week |= 1;
+        }
+        let days_of_month = parse_field(day_of_month, 1, 31)?;
+        Ok(CronSpec {
+            expression: fields.join(" "),
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days_of_month,
+            months: parse_field(month, 1, 12)?,
+            days_of_week,
+            any_day_of_month: days_of_month == EVERY_DAY_OF_MONTH,
+            any_day_of_week: days_of_week & EVERY_DAY_OF_WEEK == EVERY_DAY_OF_WEEK,
+        })
+    }
+}
+
+impl Display for CronSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2024-01-01 is a Monday.
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn next(expression: &str, after: NaiveDateTime) -> NaiveDateTime {
+        let spec: CronSpec = expression.parse().unwrap();
+        spec.next_after(after).unwrap()
+    }
+
+    #[test]
+    fn next_is_strictly_after() {
+        assert_eq!(next("30 7 * * *", at(1, 7, 29)), at(1, 7, 30));
+        assert_eq!(next("30 7 * * *", at(1, 7, 30)), at(2, 7, 30));
+        let with_seconds = at(1, 7, 29) + chrono::Duration::seconds(59);
+        assert_eq!(next("30 7 * * *", with_seconds), at(1, 7, 30));
+    }
+
+    #[test]
+    fn ranges_steps_and_lists() {
+        assert_eq!(next("*/15 * * * *", at(1, 10, 1)), at(1, 10, 15));
+        assert_eq!(next("0 9-17/4 * * *", at(1, 13, 0)), at(1, 17, 0));
+        assert_eq!(next("0 9-17/4 * * *", at(1, 17, 0)), at(2, 9, 0));
+        assert_eq!(next("5/20 * * * *", at(1, 10, 26)), at(1, 10, 45));
+        assert_eq!(next("0 8,12,18 * * *", at(1, 12, 0)), at(1, 18, 0));
+        assert_eq!(
+            next("0 0 1 3 *", at(1, 0, 0)),
+            at(1, 0, 0) + chrono::Duration::days(60)
+        );
+    }
+
+    #[test]
+    fn days_of_the_week() {
+        // Weekdays, from Saturday the 6th.
+        assert_eq!(next("30 7 * * 1-5", at(6, 8, 0)), at(8, 7, 30));
+        assert_eq!(next("0 10 * * 0", at(1, 0, 0)), at(7, 10, 0));
+        assert_eq!(next("0 10 * * 7", at(1, 0, 0)), at(7, 10, 0));
+    }
+
+    #[test]
+    fn either_day_field_matches_when_both_are_restricted() {
+        // The 10th or any Friday: Friday the 5th comes first, then the 10th (a Wednesday).
+        assert_eq!(next("0 12 10 * 5", at(1, 0, 0)), at(5, 12, 0));
+        assert_eq!(next("0 12 10 * 5", at(5, 12, 0)), at(10, 12, 0));
+        // With any day of the month, only the day of the week matters.
+        assert_eq!(next("0 12 * * 5", at(5, 12, 0)), at(12, 12, 0));
+    }
+
+    #[test]
+    fn day_fields_are_restricted_by_the_days_they_leave_out() {
+        // Every day of the month spelled out, so only Fridays.
+        assert_eq!(next("0 12 1-31 * 5", at(1, 0, 0)), at(5, 12, 0));
+        assert_eq!(next("0 12 */1 * 5", at(5, 12, 0)), at(12, 12, 0));
+        // Every day of the week spelled out, so only the 10th.
+        assert_eq!(next("0 12 10 * 0-6", at(1, 0, 0)), at(10, 12, 0));
+        assert_eq!(next("0 12 10 * 1-7", at(1, 0, 0)), at(10, 12, 0));
+        // Odd days or Fridays, starting with Monday the 1st and then Friday the 12th.
+        assert_eq!(next("0 12 */2 * 5", at(1, 0, 0)), at(1, 12, 0));
+        assert_eq!(next("0 12 */2 * 5", at(11, 12, 0)), at(12, 12, 0));
+        // Sundays, Tuesdays, Thursdays and Saturdays, on any day of the month.
+        assert_eq!(next("0 12 * * */2", at(1, 0, 0)), at(2, 12, 0));
+    }
+
+    #[test]
+    fn impossible_dates_never_run() {
+        let spec: CronSpec = "0 0 31 2 *".parse().unwrap();
+        assert_eq!(spec.next_after(at(1, 0, 0)), None);
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        for expression in [
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "10-5 * * * *",
+            "a * * * *",
+            "1,,2 * * * *",
+        ] {
+            assert!(
+                expression.parse::<CronSpec>().is_err(),
+                "{expression} should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn displays_the_normalized_expression() {
+        let spec: CronSpec = " 30  7 * *   1-5".parse().unwrap();
+        assert_eq!(spec.to_string(), "30 7 * * 1-5");
+    }
+}
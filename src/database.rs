@@ -1,25 +1,349 @@
-use sqlx::PgPool;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+
+mod memory;
+mod postgres;
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+
+/// How long a cached user is trusted before looking them up again, so a user removed from the DB
+/// loses access within this time even without a reload.
+const USER_CACHE_TTL: Duration = Duration::from_secs(60);
 
 pub struct Database {
-    con: PgPool,
+    store: Box<dyn UserStore>,
+    /// Users are looked up on every update, so they are kept in memory for [USER_CACHE_TTL].
+    /// Users missing from the cache are looked up in the DB.
+    users: RwLock<HashMap<i64, CachedUser>>,
+    /// Strangers blocked by an admin, their updates are ignored.
+    blocked: RwLock<HashSet<i64>>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, serde::Deserialize)]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum UserRole {
+    Admin,
+    Resident,
+    Guest,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct DbUser {
+    pub telegram_id: i64,
     pub name: String,
+    pub role: UserRole,
+    /// Overrides the language of the user's Telegram client, see [crate::i18n::Locale::choose].
+    pub locale: Option<String>,
+    /// Ids of the gates and lights the user can operate, all of them when `None`.
+    pub devices: Option<Vec<String>>,
+}
+
+impl DbUser {
+    pub fn can_operate(&self, device_id: &str) -> bool {
+        self.devices
+            .as_ref()
+            .is_none_or(|devices| devices.iter().any(|device| device == device_id))
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum ScheduledAction {
+    /// Unlocks the gate in `device`.
+    Open,
+    /// Turns on the light in `device` for `duration_minutes`.
+    Light,
+}
+
+impl ScheduledAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScheduledAction::Open => "open",
+            ScheduledAction::Light => "light",
+        }
+    }
+}
+
+/// An action repeated on a cron expression, see [crate::cron::CronSpec], in local time.
+#[derive(Debug, Clone)]
+pub struct DbSchedule {
+    pub id: i32,
+    pub cron: String,
+    pub action: ScheduledAction,
+    /// Gate or light id.
+    pub device: String,
+    pub duration_minutes: Option<i32>,
+    pub created_by: i64,
+}
+
+/// Second factor for opening, see [hash_pin]. Not cached since it changes on every attempt.
+#[derive(Debug, Clone, Default)]
+pub struct PinState {
+    pub pin_hash: Option<String>,
+    pub failed_attempts: i32,
+    /// Unix timestamp until which PIN entry is refused.
+    pub locked_until: Option<i64>,
+}
+
+impl PinState {
+    pub fn is_locked(&self, now: i64) -> bool {
+        self.locked_until
+            .is_some_and(|locked_until| locked_until > now)
+    }
 }
+
+#[derive(Debug, Error)]
+pub enum DbError {
+    /// The DB can't be reached right now, the same request may work later.
+    #[error("database unavailable: {0}")]
+    Unavailable(String),
+    #[error("user {0} not found")]
+    UserNotFound(i64),
+    #[error("database query failed: {0}")]
+    Query(String),
+}
+
+/// Where users, blocked strangers, PINs and schedules are kept, [Database] caches on top of it.
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn ping(&self) -> Result<(), DbError>;
+    async fn users(&self) -> Result<Vec<DbUser>, DbError>;
+    async fn blocked_users(&self) -> Result<Vec<i64>, DbError>;
+    async fn block_user(&self, telegram_id: i64) -> Result<(), DbError>;
+    /// Adds the user with the guest role, unless they already exist.
+    async fn add_guest(&self, telegram_id: i64, name: &str) -> Result<(), DbError>;
+    async fn get_user(&self, telegram_id: i64) -> Result<Option<DbUser>, DbError>;
+    /// See [hash_api_token].
+    async fn get_user_by_api_token_hash(&self, token_hash: &str)
+        -> Result<Option<DbUser>, DbError>;
+    async fn set_user_locale(&self, telegram_id: i64, locale: &str) -> Result<(), DbError>;
+    /// The default state for unknown users.
+    async fn get_pin_state(&self, telegram_id: i64) -> Result<PinState, DbError>;
+    async fn set_pin_hash(&self, telegram_id: i64, pin_hash: Option<&str>) -> Result<(), DbError>;
+    async fn reset_pin_failures(&self, telegram_id: i64) -> Result<(), DbError>;
+    async fn record_pin_failure(
+        &self,
+        telegram_id: i64,
+        max_attempts: i32,
+        lock_until: i64,
+    ) -> Result<bool, DbError>;
+    /// Ordered by id.
+    async fn schedules(&self) -> Result<Vec<DbSchedule>, DbError>;
+    async fn add_schedule(
+        &self,
+        cron: &str,
+        action: ScheduledAction,
+        device: &str,
+        duration_minutes: Option<i32>,
+        created_by: i64,
+    ) -> Result<i32, DbError>;
+    async fn remove_schedule(&self, id: i32) -> Result<bool, DbError>;
+}
+
+struct CachedUser {
+    user: DbUser,
+    cached_at: Instant,
+}
+
+impl CachedUser {
+    fn new(user: DbUser) -> Self {
+        Self {
+            user,
+            cached_at: Instant::now(),
+        }
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.cached_at.elapsed() < USER_CACHE_TTL
+    }
+}
+
 impl Database {
-    pub fn new(con: PgPool) -> Database {
-        Self { con }
-    }
-    pub async fn get_user(&self, telegram_id: i64) -> Result<Option<DbUser>, String> {
-        let user: Option<DbUser> = sqlx::query_as!(
-            DbUser,
-            "select name from users where telegram_id = $1 ;",
-            telegram_id
-        )
-        .fetch_optional(&self.con)
-        .await
-        .map_err(|e| e.to_string())?;
+    pub fn new(store: impl UserStore + 'static) -> Database {
+        Self {
+            store: Box::new(store),
+            users: RwLock::new(HashMap::new()),
+            blocked: RwLock::new(HashSet::new()),
+        }
+    }
+    pub async fn ping(&self) -> Result<(), DbError> {
+        self.store.ping().await
+    }
+
+    /// Reloads every user and blocked stranger from the DB, returning how many users there are.
+    pub async fn refresh_users(&self) -> Result<usize, DbError> {
+        let users: HashMap<i64, CachedUser> = self
+            .store
+            .users()
+            .await?
+            .into_iter()
+            .map(|user| (user.telegram_id, CachedUser::new(user)))
+            .collect();
+        let count = users.len();
+        *self.users.write().await = users;
+        let blocked: HashSet<i64> = self.store.blocked_users().await?.into_iter().collect();
+        *self.blocked.write().await = blocked;
+        Ok(count)
+    }
+
+    pub async fn is_blocked(&self, telegram_id: i64) -> bool {
+        self.blocked.read().await.contains(&telegram_id)
+    }
+
+    pub async fn block_user(&self, telegram_id: i64) -> Result<(), DbError> {
+        self.store.block_user(telegram_id).await?;
+        self.blocked.write().await.insert(telegram_id);
+        Ok(())
+    }
+
+    /// Adds the user with the guest role, unless they already exist.
+    pub async fn add_guest(&self, telegram_id: i64, name: &str) -> Result<DbUser, DbError> {
+        self.store.add_guest(telegram_id, name).await?;
+        self.get_user(telegram_id)
+            .await?
+            .ok_or(DbError::UserNotFound(telegram_id))
+    }
+
+    /// Looks the user up again once their cache entry is older than [USER_CACHE_TTL], forgetting
+    /// them if they were removed.
+    pub async fn get_user(&self, telegram_id: i64) -> Result<Option<DbUser>, DbError> {
+        if let Some(cached) = self.users.read().await.get(&telegram_id) {
+            if cached.is_fresh() {
+                return Ok(Some(cached.user.clone()));
+            }
+        }
+        let user = self.store.get_user(telegram_id).await?;
+        let mut users = self.users.write().await;
+        match &user {
+            Some(user) => {
+                users.insert(telegram_id, CachedUser::new(user.clone()));
+            }
+            None => {
+                users.remove(&telegram_id);
+            }
+        }
         Ok(user)
     }
+
+    pub async fn is_cached(&self, telegram_id: i64) -> bool {
+        self.users.read().await.contains_key(&telegram_id)
+    }
+
+    /// Cached users with any of the roles, see [Database::refresh_users]. Removed users may still
+    /// be among them, but [Database::get_user] stops them from acting on what they are sent.
+    pub async fn users_with_roles(&self, roles: &[UserRole]) -> Vec<DbUser> {
+        self.users
+            .read()
+            .await
+            .values()
+            .filter(|cached| roles.contains(&cached.user.role))
+            .map(|cached| cached.user.clone())
+            .collect()
+    }
+
+    pub async fn set_user_locale(&self, telegram_id: i64, locale: &str) -> Result<(), DbError> {
+        self.store.set_user_locale(telegram_id, locale).await?;
+        if let Some(cached) = self.users.write().await.get_mut(&telegram_id) {
+            cached.user.locale = Some(locale.to_string());
+        }
+        Ok(())
+    }
+
+    pub async fn get_pin_state(&self, telegram_id: i64) -> Result<PinState, DbError> {
+        self.store.get_pin_state(telegram_id).await
+    }
+
+    /// Also clears any failed attempts, `None` removes the PIN.
+    pub async fn set_pin_hash(
+        &self,
+        telegram_id: i64,
+        pin_hash: Option<&str>,
+    ) -> Result<(), DbError> {
+        self.store.set_pin_hash(telegram_id, pin_hash).await
+    }
+
+    pub async fn reset_pin_failures(&self, telegram_id: i64) -> Result<(), DbError> {
+        self.store.reset_pin_failures(telegram_id).await
+    }
+
+    /// Counts a wrong PIN, locking PIN entry until `lock_until` once `max_attempts` is reached.
+    /// Returns whether it got locked.
+    pub async fn record_pin_failure(
+        &self,
+        telegram_id: i64,
+        max_attempts: i32,
+        lock_until: i64,
+    ) -> Result<bool, DbError> {
+        self.store
+            .record_pin_failure(telegram_id, max_attempts, lock_until)
+            .await
+    }
+
+    /// Not cached, only the scheduler reads them and it does so when they change.
+    pub async fn schedules(&self) -> Result<Vec<DbSchedule>, DbError> {
+        self.store.schedules().await
+    }
+
+    /// Returns the id of the new schedule.
+    pub async fn add_schedule(
+        &self,
+        cron: &str,
+        action: ScheduledAction,
+        device: &str,
+        duration_minutes: Option<i32>,
+        created_by: i64,
+    ) -> Result<i32, DbError> {
+        self.store
+            .add_schedule(cron, action, device, duration_minutes, created_by)
+            .await
+    }
+
+    /// Returns whether there was such a schedule.
+    pub async fn remove_schedule(&self, id: i32) -> Result<bool, DbError> {
+        self.store.remove_schedule(id).await
+    }
+
+    /// Tokens are stored as hex encoded SHA-256 hashes, so a leaked DB dump can't open the gate.
+    pub async fn get_user_by_api_token(&self, token: &str) -> Result<Option<DbUser>, DbError> {
+        self.store
+            .get_user_by_api_token_hash(&hash_api_token(token))
+            .await
+    }
+}
+
+pub fn hash_api_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// PINs are short, so unlike API tokens they are hashed with a slow, salted hash (argon2).
+pub fn hash_pin(pin: &str) -> String {
+    let salt =
+        SaltString::encode_b64(&rand::random::<[u8; 16]>()).expect("16 bytes is a valid salt");
+    Argon2::default()
+        .hash_password(pin.as_bytes(), &salt)
+        .expect("hashing with default params works")
+        .to_string()
+}
+
+pub fn verify_pin(pin: &str, pin_hash: &str) -> bool {
+    match PasswordHash::new(pin_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(pin.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            tracing::error!("Invalid PIN hash in the DB: {e}");
+            false
+        }
+    }
 }
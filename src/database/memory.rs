@@ -0,0 +1,330 @@
+use crate::database::{
+    DbError, DbSchedule, DbUser, PinState, ScheduledAction, UserRole, UserStore,
+};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Default)]
+struct Tables {
+    users: BTreeMap<i64, StoredUser>,
+    blocked: HashSet<i64>,
+    schedules: BTreeMap<i32, DbSchedule>,
+    last_schedule_id: i32,
+}
+
+struct StoredUser {
+    user: DbUser,
+    pin: PinState,
+    api_token_hash: Option<String>,
+}
+
+/// Keeps everything in memory, behaving like the tables in `migrations`. Used by the tests and by
+/// [crate::config::DatabaseKind::Memory]. Clones share the tables, so tests can keep one while
+/// [crate::database::Database] owns another.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    tables: Arc<Mutex<Tables>>,
+    unavailable: Arc<AtomicBool>,
+}
+
+impl MemoryStore {
+    pub fn new(users: Vec<DbUser>) -> MemoryStore {
+        let store = MemoryStore::default();
+        for user in users {
+            store.insert_user(user);
+        }
+        store
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Tables> {
+        self.tables.lock().expect("memory store lock poisoned")
+    }
+
+    fn tables(&self) -> Result<MutexGuard<'_, Tables>, DbError> {
+        if self.unavailable.load(Ordering::Relaxed) {
+            return Err(DbError::Unavailable("simulated outage".to_string()));
+        }
+        Ok(self.lock())
+    }
+
+    /// Fails every request with [DbError::Unavailable] until set back.
+    #[cfg(test)]
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.unavailable.store(unavailable, Ordering::Relaxed);
+    }
+
+    /// Like deleting the row by hand, there is no command for it.
+    #[cfg(test)]
+    pub fn remove_user(&self, telegram_id: i64) {
+        self.lock().users.remove(&telegram_id);
+    }
+
+    fn insert_user(&self, user: DbUser) {
+        self.lock().users.insert(
+            user.telegram_id,
+            StoredUser {
+                user,
+                pin: PinState::default(),
+                api_token_hash: None,
+            },
+        );
+    }
+
+    fn with_user<R>(
+        &self,
+        telegram_id: i64,
+        f: impl FnOnce(&mut StoredUser) -> R,
+    ) -> Result<R, DbError> {
+        self.tables()?
+            .users
+            .get_mut(&telegram_id)
+            .map(f)
+            .ok_or(DbError::UserNotFound(telegram_id))
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn ping(&self) -> Result<(), DbError> {
+        self.tables().map(|_| ())
+    }
+
+    async fn users(&self) -> Result<Vec<DbUser>, DbError> {
+        Ok(self
+            .tables()?
+            .users
+            .values()
+            .map(|stored| stored.user.clone())
+            .collect())
+    }
+
+    async fn blocked_users(&self) -> Result<Vec<i64>, DbError> {
+        Ok(self.tables()?.blocked.iter().copied().collect())
+    }
+
+    async fn block_user(&self, telegram_id: i64) -> Result<(), DbError> {
+        self.tables()?.blocked.insert(telegram_id);
+        Ok(())
+    }
+
+    async fn add_guest(&self, telegram_id: i64, name: &str) -> Result<(), DbError> {
+        let mut tables = self.tables()?;
+        tables
+            .users
+            .entry(telegram_id)
+            .or_insert_with(|| StoredUser {
+                user: DbUser {
+                    telegram_id,
+                    name: name.to_string(),
+                    role: UserRole::Guest,
+                    locale: None,
+                    devices: None,
+                },
+                pin: PinState::default(),
+                api_token_hash: None,
+            });
+        Ok(())
+    }
+
+    async fn get_user(&self, telegram_id: i64) -> Result<Option<DbUser>, DbError> {
+        Ok(self
+            .tables()?
+            .users
+            .get(&telegram_id)
+            .map(|stored| stored.user.clone()))
+    }
+
+    async fn get_user_by_api_token_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<DbUser>, DbError> {
+        Ok(self
+            .tables()?
+            .users
+            .values()
+            .find(|stored| stored.api_token_hash.as_deref() == Some(token_hash))
+            .map(|stored| stored.user.clone()))
+    }
+
+    async fn set_user_locale(&self, telegram_id: i64, locale: &str) -> Result<(), DbError> {
+        self.with_user(telegram_id, |stored| {
+            stored.user.locale = Some(locale.to_string())
+        })
+    }
+
+    async fn get_pin_state(&self, telegram_id: i64) -> Result<PinState, DbError> {
+        Ok(self
+            .tables()?
+            .users
+            .get(&telegram_id)
+            .map(|stored| stored.pin.clone())
+            .unwrap_or_default())
+    }
+
+    async fn set_pin_hash(&self, telegram_id: i64, pin_hash: Option<&str>) -> Result<(), DbError> {
+        self.with_user(telegram_id, |stored| {
+            stored.pin = PinState {
+                pin_hash: pin_hash.map(str::to_string),
+                failed_attempts: 0,
+                locked_until: None,
+            }
+        })
+    }
+
+    async fn reset_pin_failures(&self, telegram_id: i64) -> Result<(), DbError> {
+        self.with_user(telegram_id, |stored| {
+            stored.pin.failed_attempts = 0;
+            stored.pin.locked_until = None;
+        })
+    }
+
+    async fn record_pin_failure(
+        &self,
+        telegram_id: i64,
+        max_attempts: i32,
+        lock_until: i64,
+    ) -> Result<bool, DbError> {
+        self.with_user(telegram_id, |stored| {
+            if stored.pin.failed_attempts + 1 >= max_attempts {
+                stored.pin.failed_attempts = 0;
+                stored.pin.locked_until = Some(lock_until);
+                true
+            } else {
+                stored.pin.failed_attempts += 1;
+                false
+            }
+        })
+    }
+
+    async fn schedules(&self) -> Result<Vec<DbSchedule>, DbError> {
+        Ok(self.tables()?.schedules.values().cloned().collect())
+    }
+
+    async fn add_schedule(
+        &self,
+        cron: &str,
+        action: ScheduledAction,
+        device: &str,
+        duration_minutes: Option<i32>,
+        created_by: i64,
+    ) -> Result<i32, DbError> {
+        let mut tables = self.tables()?;
+        tables.last_schedule_id += 1;
+        let id = tables.last_schedule_id;
+        tables.schedules.insert(
+            id,
+            DbSchedule {
+                id,
+                cron: cron.to_string(),
+                action,
+                device: device.to_string(),
+                duration_minutes,
+                created_by,
+            },
+        );
+        Ok(id)
+    }
+
+    async fn remove_schedule(&self, id: i32) -> Result<bool, DbError> {
+        Ok(self.tables()?.schedules.remove(&id).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+
+    fn resident(telegram_id: i64) -> DbUser {
+        DbUser {
+            telegram_id,
+            name: format!("Resident {telegram_id}"),
+            role: UserRole::Resident,
+            locale: None,
+            devices: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn pin_entry_locks_after_the_last_attempt() {
+        let store = MemoryStore::new(vec![resident(1)]);
+        store.set_pin_hash(1, Some("hash")).await.unwrap();
+        assert!(!store.record_pin_failure(1, 3, 1000).await.unwrap());
+        assert!(!store.record_pin_failure(1, 3, 1000).await.unwrap());
+        assert!(store.record_pin_failure(1, 3, 1000).await.unwrap());
+        let pin = store.get_pin_state(1).await.unwrap();
+        assert_eq!(pin.failed_attempts, 0);
+        assert_eq!(pin.locked_until, Some(1000));
+        assert_eq!(pin.pin_hash.as_deref(), Some("hash"));
+        store.reset_pin_failures(1).await.unwrap();
+        assert!(!store.get_pin_state(1).await.unwrap().is_locked(0));
+    }
+
+    #[tokio::test]
+    async fn unknown_users_have_no_pin() {
+        let store = MemoryStore::default();
+        assert!(store.get_pin_state(1).await.unwrap().pin_hash.is_none());
+        assert!(store.set_pin_hash(1, Some("hash")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn adding_an_existing_user_as_guest_keeps_their_role() {
+        let store = MemoryStore::new(vec![resident(1)]);
+        store.add_guest(1, "Someone else").await.unwrap();
+        store.add_guest(2, "Visitor").await.unwrap();
+        let users = store.users().await.unwrap();
+        let roles: Vec<(i64, UserRole)> = users.iter().map(|u| (u.telegram_id, u.role)).collect();
+        assert_eq!(roles, vec![(1, UserRole::Resident), (2, UserRole::Guest)]);
+        assert_eq!(users[0].name, "Resident 1");
+    }
+
+    #[tokio::test]
+    async fn schedule_ids_are_not_reused() {
+        let store = MemoryStore::default();
+        let first = store
+            .add_schedule("0 7 * * *", ScheduledAction::Open, "gate", None, 1)
+            .await
+            .unwrap();
+        assert!(store.remove_schedule(first).await.unwrap());
+        assert!(!store.remove_schedule(first).await.unwrap());
+        let second = store
+            .add_schedule(
+                "0 19 * * *",
+                ScheduledAction::Light,
+                "spotlight",
+                Some(30),
+                1,
+            )
+            .await
+            .unwrap();
+        assert_ne!(first, second);
+        let schedules = store.schedules().await.unwrap();
+        assert_eq!(schedules.len(), 1);
+        assert_eq!(schedules[0].duration_minutes, Some(30));
+    }
+
+    #[tokio::test]
+    async fn database_caches_users_from_the_store() {
+        let db = Database::new(MemoryStore::new(vec![resident(1)]));
+        assert!(!db.is_cached(1).await);
+        assert!(db.get_user(1).await.unwrap().is_some());
+        assert!(db.is_cached(1).await);
+        assert!(db.get_user(2).await.unwrap().is_none());
+        assert!(!db.is_cached(2).await);
+        db.set_user_locale(1, "en").await.unwrap();
+        let user = db.get_user(1).await.unwrap().unwrap();
+        assert_eq!(user.locale.as_deref(), Some("en"));
+    }
+
+    #[tokio::test]
+    async fn blocked_strangers_survive_a_refresh() {
+        let db = Database::new(MemoryStore::new(vec![resident(1)]));
+        db.block_user(99).await.unwrap();
+        assert!(db.is_blocked(99).await);
+        assert_eq!(db.refresh_users().await.unwrap(), 1);
+        assert!(db.is_blocked(99).await);
+        assert!(!db.is_blocked(1).await);
+    }
+}
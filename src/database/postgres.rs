@@ -0,0 +1,226 @@
+use crate::database::{
+    DbError, DbSchedule, DbUser, PinState, ScheduledAction, UserRole, UserStore,
+};
+use crate::metrics;
+use async_trait::async_trait;
+use sqlx::{Connection, PgPool};
+
+impl From<sqlx::Error> for DbError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => DbError::Unavailable(e.to_string()),
+            e => DbError::Query(e.to_string()),
+        }
+    }
+}
+
+pub struct PostgresStore {
+    con: PgPool,
+}
+
+impl PostgresStore {
+    pub fn new(con: PgPool) -> PostgresStore {
+        Self { con }
+    }
+}
+
+#[async_trait]
+impl UserStore for PostgresStore {
+    async fn ping(&self) -> Result<(), DbError> {
+        let mut con = self.con.acquire().await.map_err(DbError::from)?;
+        con.ping().await.map_err(DbError::from)
+    }
+
+    async fn users(&self) -> Result<Vec<DbUser>, DbError> {
+        sqlx::query_as!(
+            DbUser,
+            r#"select telegram_id, name, role as "role: UserRole", locale, devices from users ;"#
+        )
+        .fetch_all(&self.con)
+        .await
+        .map_err(|e| {
+            metrics::DB_LOOKUP_FAILURES.inc();
+            DbError::from(e)
+        })
+    }
+
+    async fn blocked_users(&self) -> Result<Vec<i64>, DbError> {
+        sqlx::query_scalar!("select telegram_id from blocked_users ;")
+            .fetch_all(&self.con)
+            .await
+            .map_err(|e| {
+                metrics::DB_LOOKUP_FAILURES.inc();
+                DbError::from(e)
+            })
+    }
+
+    async fn block_user(&self, telegram_id: i64) -> Result<(), DbError> {
+        sqlx::query!(
+            "insert into blocked_users (telegram_id) values ($1) on conflict do nothing ;",
+            telegram_id
+        )
+        .execute(&self.con)
+        .await
+        .map_err(DbError::from)?;
+        Ok(())
+    }
+
+    async fn add_guest(&self, telegram_id: i64, name: &str) -> Result<(), DbError> {
+        sqlx::query!(
+            "insert into users (telegram_id, name, role) values ($1, $2, 'guest') on conflict (telegram_id) do nothing ;",
+            telegram_id,
+            name
+        )
+        .execute(&self.con)
+        .await
+        .map_err(DbError::from)?;
+        Ok(())
+    }
+
+    async fn get_user(&self, telegram_id: i64) -> Result<Option<DbUser>, DbError> {
+        sqlx::query_as!(
+            DbUser,
+            r#"select telegram_id, name, role as "role: UserRole", locale, devices from users where telegram_id = $1 ;"#,
+            telegram_id
+        )
+        .fetch_optional(&self.con)
+        .await
+        .map_err(|e| {
+            metrics::DB_LOOKUP_FAILURES.inc();
+            DbError::from(e)
+        })
+    }
+
+    async fn get_user_by_api_token_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<DbUser>, DbError> {
+        sqlx::query_as!(
+            DbUser,
+            r#"select telegram_id, name, role as "role: UserRole", locale, devices from users where api_token_hash = $1 ;"#,
+            token_hash
+        )
+        .fetch_optional(&self.con)
+        .await
+        .map_err(|e| {
+            metrics::DB_LOOKUP_FAILURES.inc();
+            DbError::from(e)
+        })
+    }
+
+    async fn set_user_locale(&self, telegram_id: i64, locale: &str) -> Result<(), DbError> {
+        sqlx::query!(
+            "update users set locale = $1 where telegram_id = $2 ;",
+            locale,
+            telegram_id
+        )
+        .execute(&self.con)
+        .await
+        .map_err(DbError::from)?;
+        Ok(())
+    }
+
+    async fn get_pin_state(&self, telegram_id: i64) -> Result<PinState, DbError> {
+        let pin_state = sqlx::query_as!(
+            PinState,
+            r#"select pin_hash, pin_failed_attempts as failed_attempts, pin_locked_until as locked_until from users where telegram_id = $1 ;"#,
+            telegram_id
+        )
+        .fetch_optional(&self.con)
+        .await
+        .map_err(|e| {
+            metrics::DB_LOOKUP_FAILURES.inc();
+            DbError::from(e)
+        })?;
+        Ok(pin_state.unwrap_or_default())
+    }
+
+    async fn set_pin_hash(&self, telegram_id: i64, pin_hash: Option<&str>) -> Result<(), DbError> {
+        sqlx::query!(
+            "update users set pin_hash = $1, pin_failed_attempts = 0, pin_locked_until = null where telegram_id = $2 ;",
+            pin_hash,
+            telegram_id
+        )
+        .execute(&self.con)
+        .await
+        .map_err(DbError::from)?;
+        Ok(())
+    }
+
+    async fn reset_pin_failures(&self, telegram_id: i64) -> Result<(), DbError> {
+        sqlx::query!(
+            "update users set pin_failed_attempts = 0, pin_locked_until = null where telegram_id = $1 ;",
+            telegram_id
+        )
+        .execute(&self.con)
+        .await
+        .map_err(DbError::from)?;
+        Ok(())
+    }
+
+    async fn record_pin_failure(
+        &self,
+        telegram_id: i64,
+        max_attempts: i32,
+        lock_until: i64,
+    ) -> Result<bool, DbError> {
+        let row = sqlx::query!(
+            r#"update users set
+                pin_failed_attempts = case when pin_failed_attempts + 1 >= $2 then 0 else pin_failed_attempts + 1 end,
+                pin_locked_until = case when pin_failed_attempts + 1 >= $2 then $3 else pin_locked_until end
+            where telegram_id = $1 returning pin_locked_until ;"#,
+            telegram_id,
+            max_attempts,
+            lock_until
+        )
+        .fetch_optional(&self.con)
+        .await
+        .map_err(DbError::from)?
+        .ok_or(DbError::UserNotFound(telegram_id))?;
+        Ok(row.pin_locked_until == Some(lock_until))
+    }
+
+    async fn schedules(&self) -> Result<Vec<DbSchedule>, DbError> {
+        sqlx::query_as!(
+            DbSchedule,
+            r#"select id, cron, action as "action: ScheduledAction", device, duration_minutes, created_by from schedules order by id ;"#
+        )
+        .fetch_all(&self.con)
+        .await
+        .map_err(DbError::from)
+    }
+
+    async fn add_schedule(
+        &self,
+        cron: &str,
+        action: ScheduledAction,
+        device: &str,
+        duration_minutes: Option<i32>,
+        created_by: i64,
+    ) -> Result<i32, DbError> {
+        let row = sqlx::query!(
+            "insert into schedules (cron, action, device, duration_minutes, created_by) values ($1, $2, $3, $4, $5) returning id ;",
+            cron,
+            action.as_str(),
+            device,
+            duration_minutes,
+            created_by
+        )
+        .fetch_one(&self.con)
+        .await
+        .map_err(DbError::from)?;
+        Ok(row.id)
+    }
+
+    async fn remove_schedule(&self, id: i32) -> Result<bool, DbError> {
+        let result = sqlx::query!("delete from schedules where id = $1 ;", id)
+            .execute(&self.con)
+            .await
+            .map_err(DbError::from)?;
+        Ok(result.rows_affected() > 0)
+    }
+}
@@ -0,0 +1,246 @@
+use crate::hardware::RawHardware;
+use crate::metrics;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::spawn_blocking;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+/// What [HardwareActor] can be asked to do, each carrying the channel its reply goes to.
+pub(super) enum Command {
+    UnlockGate {
+        gate: usize,
+        done: oneshot::Sender<()>,
+    },
+    /// Keeps the spotlight on until `duration` from now, whether it was on or not.
+    TurnOnSpotlight {
+        light: usize,
+        duration: Duration,
+        done: oneshot::Sender<()>,
+    },
+    /// Replies with the time the spotlight will now stay on.
+    ExtendSpotlight {
+        light: usize,
+        extra: Duration,
+        remaining: oneshot::Sender<Duration>,
+    },
+    TurnOffSpotlight {
+        light: usize,
+        done: oneshot::Sender<()>,
+    },
+    ResetOutputs {
+        done: oneshot::Sender<()>,
+    },
+    ReadGateSensor {
+        gate: usize,
+        closed: oneshot::Sender<Option<bool>>,
+    },
+    /// Answered as soon as the actor gets to it, to check it isn't stuck.
+    Ping {
+        done: oneshot::Sender<()>,
+    },
+}
+
+struct SpotlightState {
+    turn_off_at: Option<Instant>,
+    on_since: Option<Instant>,
+    watch: watch::Sender<Option<Instant>>,
+}
+
+impl SpotlightState {
+    fn set_turn_off_at(&mut self, turn_off_at: Option<Instant>) {
+        match (self.on_since, turn_off_at) {
+            (None, Some(_)) => self.on_since = Some(Instant::now()),
+            (Some(on_since), None) => {
+                metrics::SPOTLIGHT_ON_SECONDS.inc_by(on_since.elapsed().as_secs_f64());
+                self.on_since = None;
+            }
+            _ => {}
+        }
+        self.turn_off_at = turn_off_at;
+        self.watch.send_replace(turn_off_at);
+    }
+}
+
+/// Owns the pins, so every hardware access is serialized through its command channel. Lights are
+/// turned off by a single timer, reset to the earliest deadline after every command.
+pub(super) struct HardwareActor<T: RawHardware> {
+    /// Only `None` while driven on the blocking pool, or for good once it panicked.
+    hardware: Option<T>,
+    spotlights: Vec<SpotlightState>,
+}
+
+impl<T: RawHardware> HardwareActor<T> {
+    /// Also returns a receiver per light with the instant it will be turned off.
+    pub(super) fn new(hardware: T, lights: usize) -> (Self, Vec<watch::Receiver<Option<Instant>>>) {
+        let (spotlights, receivers) = (0..lights)
+            .map(|_| {
+                let (watch, receiver) = watch::channel(None);
+                let spotlight = SpotlightState {
+                    turn_off_at: None,
+                    on_since: None,
+                    watch,
+                };
+                (spotlight, receiver)
+            })
+            .unzip();
+        (
+            Self {
+                hardware: Some(hardware),
+                spotlights,
+            },
+            receivers,
+        )
+    }
+
+    /// Runs until every sender is dropped, then drives the outputs low. Also stops if the hardware
+    /// panics, dropping the receiver so the handle can tell.
+    pub(super) async fn run(mut self, mut commands: mpsc::Receiver<Command>) {
+        let timer = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(timer);
+        loop {
+            let next = self.next_turn_off();
+            if let Some((_, turn_off_at)) = next {
+                timer.as_mut().reset(turn_off_at);
+            }
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => self.handle(command).await,
+                    None => break,
+                },
+                () = &mut timer, if next.is_some() => {
+                    let (light, _) = next.expect("timer only armed with a deadline");
+                    info!("Its time to turn off spotlight {light}, turning it off");
+                    self.turn_off_spotlight(light).await;
+                }
+            }
+            if self.hardware.is_none() {
+                error!("Hardware failed, its outputs were reset and it won't be driven again");
+                for spotlight in &mut self.spotlights {
+                    spotlight.set_turn_off_at(None);
+                }
+                return;
+            }
+        }
+        warn!("Hardware handle dropped, resetting outputs");
+        self.reset_outputs().await;
+    }
+
+    /// Runs `f` on the blocking pool, since driving pins is file IO and the gate pulse sleeps.
+    /// `None` if it panicked, in which case the outputs were reset and the hardware dropped.
+    async fn drive<R: Send + 'static>(
+        &mut self,
+        f: impl FnOnce(&mut T) -> R + Send + 'static,
+    ) -> Option<R> {
+        let mut hardware = self.hardware.take()?;
+        let driven = spawn_blocking(move || {
+            let result = catch_unwind(AssertUnwindSafe(|| f(&mut hardware)));
+            if result.is_err() {
+                hardware.reset_outputs();
+            }
+            (hardware, result)
+        })
+        .await;
+        match driven {
+            Ok((hardware, Ok(result))) => {
+                self.hardware = Some(hardware);
+                Some(result)
+            }
+            // the panic itself was logged by the panic hook
+            Ok((_, Err(_))) => None,
+            Err(e) => {
+                error!("Driving the hardware: {e}");
+                None
+            }
+        }
+    }
+
+    fn next_turn_off(&self) -> Option<(usize, Instant)> {
+        self.spotlights
+            .iter()
+            .enumerate()
+            .filter_map(|(light, spotlight)| spotlight.turn_off_at.map(|at| (light, at)))
+            .min_by_key(|(_, turn_off_at)| *turn_off_at)
+    }
+
+    /// Replies are dropped without an answer when the hardware fails.
+    async fn handle(&mut self, command: Command) {
+        // a dropped reply only means whoever asked stopped waiting, the command still happened
+        match command {
+            Command::UnlockGate { gate, done } => {
+                if self.drive(move |hw| hw.unlock_gate(gate)).await.is_some() {
+                    let _ = done.send(());
+                }
+            }
+            Command::TurnOnSpotlight {
+                light,
+                duration,
+                done,
+            } => {
+                if self
+                    .drive(move |hw| hw.turn_on_spotlight(light))
+                    .await
+                    .is_some()
+                {
+                    self.spotlights[light].set_turn_off_at(Some(Instant::now() + duration));
+                    let _ = done.send(());
+                }
+            }
+            Command::ExtendSpotlight {
+                light,
+                extra,
+                remaining,
+            } => {
+                let now = Instant::now();
+                let turn_off_at = match self.spotlights[light].turn_off_at {
+                    Some(turn_off_at) => turn_off_at.max(now) + extra,
+                    None => {
+                        if self
+                            .drive(move |hw| hw.turn_on_spotlight(light))
+                            .await
+                            .is_none()
+                        {
+                            return;
+                        }
+                        now + extra
+                    }
+                };
+                self.spotlights[light].set_turn_off_at(Some(turn_off_at));
+                let _ = remaining.send(turn_off_at - now);
+            }
+            Command::TurnOffSpotlight { light, done } => {
+                if self.turn_off_spotlight(light).await.is_some() {
+                    let _ = done.send(());
+                }
+            }
+            Command::ResetOutputs { done } => {
+                if self.reset_outputs().await.is_some() {
+                    let _ = done.send(());
+                }
+            }
+            Command::ReadGateSensor { gate, closed } => {
+                if let Some(is_closed) = self.drive(move |hw| hw.is_gate_closed(gate)).await {
+                    let _ = closed.send(is_closed);
+                }
+            }
+            Command::Ping { done } => {
+                let _ = done.send(());
+            }
+        }
+    }
+
+    async fn turn_off_spotlight(&mut self, light: usize) -> Option<()> {
+        self.drive(move |hw| hw.turn_off_spotlight(light)).await?;
+        self.spotlights[light].set_turn_off_at(None);
+        Some(())
+    }
+
+    async fn reset_outputs(&mut self) -> Option<()> {
+        self.drive(|hw| hw.reset_outputs()).await?;
+        for spotlight in &mut self.spotlights {
+            spotlight.set_turn_off_at(None);
+        }
+        Some(())
+    }
+}
@@ -1,16 +1,25 @@
-use std::sync::Arc;
+use crate::config::HardwareConfig;
+use actor::{Command, HardwareActor};
+use std::marker::PhantomData;
 use std::thread::sleep;
 use std::time::Duration;
 use sysfs_gpio::{Direction, Pin};
-use tokio::sync::RwLock;
-use tracing::info;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
 
-const GATE: u64 = 26;
-const SPOTLIGHT: u64 = 17;
+mod actor;
+#[cfg(test)]
+pub mod simulator;
+
+/// How long the gate relay is held to unlock it.
+pub const GATE_PULSE: Duration = Duration::from_millis(500);
 
 pub struct RealHardware {
-    gate: Pin,
-    spotlight: Pin,
+    gates: Vec<Pin>,
+    gate_sensors: Vec<Option<Pin>>,
+    lights: Vec<Pin>,
 }
 
 pub struct MockHardware {}
@@ -21,151 +30,293 @@ impl MockHardware {
     }
 }
 
-pub struct GateHardwareInner<T: RawHardware> {
-    hardware: T,
-    instant_to_turn_off: Option<std::time::Instant>,
-}
-
+/// Handle to the task owning the hardware, see [HardwareActor]. Gates and lights are referred to
+/// by their position in [HardwareConfig::gates] and [HardwareConfig::lights].
 pub struct RefCountedGateHardware<T: RawHardware> {
-    inner: Arc<RwLock<GateHardwareInner<T>>>,
+    commands: mpsc::Sender<Command>,
+    spotlights: Vec<watch::Receiver<Option<Instant>>>,
+    hardware: PhantomData<fn() -> T>,
 }
 
 impl<T: RawHardware> RefCountedGateHardware<T> {
-    pub fn new_mock() -> RefCountedGateHardware<MockHardware> {
-        RefCountedGateHardware {
-            inner: Arc::new(RwLock::new(GateHardwareInner {
-                hardware: MockHardware::new(),
-                instant_to_turn_off: None,
-            })),
-        }
+    pub fn new_mock(config: &HardwareConfig) -> RefCountedGateHardware<MockHardware> {
+        RefCountedGateHardware::spawn(MockHardware::new(), config.lights.len())
     }
 
-    pub fn new_real_hardware() -> RefCountedGateHardware<RealHardware> {
-        RefCountedGateHardware {
-            inner: Arc::new(RwLock::new(GateHardwareInner {
-                hardware: RealHardware::new(),
-                instant_to_turn_off: None,
-            })),
-        }
+    pub fn new_real_hardware(config: &HardwareConfig) -> RefCountedGateHardware<RealHardware> {
+        RefCountedGateHardware::spawn(
+            RealHardware::new(
+                &config.gate_pins(),
+                &config.gate_sensor_pins(),
+                &config.light_pins(),
+            ),
+            config.lights.len(),
+        )
     }
 
-    fn new_ref_counted(&self) -> RefCountedGateHardware<T> {
+    /// Spawns the task owning the hardware, so must be called from within the runtime. The task
+    /// stops, driving the outputs low, when the handle is dropped.
+    pub fn spawn(hardware: T, lights: usize) -> Self {
+        let (actor, spotlights) = HardwareActor::new(hardware, lights);
+        let (commands, receiver) = mpsc::channel(16);
+        tokio::spawn(actor.run(receiver));
         Self {
-            inner: Arc::clone(&self.inner),
+            commands,
+            spotlights,
+            hardware: PhantomData,
+        }
+    }
+
+    /// Sends the command and waits for the reply, `None` if the hardware task is gone (it panicked
+    /// driving a pin).
+    async fn request<R>(&self, command: impl FnOnce(oneshot::Sender<R>) -> Command) -> Option<R> {
+        let (reply, replied) = oneshot::channel();
+        if self.commands.send(command(reply)).await.is_err() {
+            error!("Hardware task is gone");
+            return None;
         }
+        replied.await.ok()
     }
 
-    pub async fn is_spotlight_on(&self) -> bool {
-        self.inner.read().await.instant_to_turn_off.is_some()
+    /// Hangs while the hardware task is busy driving pins, false if it is gone.
+    pub async fn is_responsive(&self) -> bool {
+        self.request(|done| Command::Ping { done }).await.is_some()
     }
 
-    async fn check_spotlight_should_be_turned_off(&self) {
-        let mut write_ref = self.inner.write().await;
-        if let Some(instant_to_turn_off) = &write_ref.instant_to_turn_off {
-            if &std::time::Instant::now() >= instant_to_turn_off {
-                info!("Its time to turn off spotlight, turning it off");
-                write_ref.hardware.turn_off_spotlight();
-                write_ref.instant_to_turn_off.take();
-            } else {
-                info!("Its not time yet to turn off spotlight");
+    pub fn is_spotlight_on(&self, light: usize) -> bool {
+        self.spotlights[light].borrow().is_some()
+    }
+
+    pub fn spotlight_remaining(&self, light: usize) -> Option<Duration> {
+        self.spotlights[light]
+            .borrow()
+            .map(|turn_off_at| turn_off_at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Receives the instant the spotlight will be turned off on every change, `None` when it is off.
+    pub fn subscribe_spotlight(&self, light: usize) -> watch::Receiver<Option<Instant>> {
+        self.spotlights[light].clone()
+    }
+
+    /// Waits for the controller task to finish and, if it ever does, forces every output low so
+    /// the relay or spotlight are not left on after a crash. If the hardware task stops first,
+    /// having reset the outputs after a panic, the controller is stopped instead, since nothing it
+    /// does could reach the hardware anymore.
+    pub async fn watchdog(&self, mut controller: JoinHandle<()>) {
+        tokio::select! {
+            result = &mut controller => {
+                match result {
+                    Ok(()) => warn!("Controller task finished, resetting outputs"),
+                    Err(e) => error!("Controller task died: {e}, resetting outputs"),
+                }
+                self.request(|done| Command::ResetOutputs { done }).await;
+            }
+            () = self.commands.closed() => {
+                error!("Hardware task died, stopping the controller");
+                controller.abort();
             }
-        } else {
-            info!("There is no time to turn off the spotlight, must already be off");
         }
     }
 
-    pub async fn unlock_gate(&self) {
-        self.inner.write().await.hardware.unlock_gate();
+    /// `None` when the gate has no sensor or the hardware task is gone.
+    pub async fn is_gate_closed(&self, gate: usize) -> Option<bool> {
+        self.request(|closed| Command::ReadGateSensor { gate, closed })
+            .await
+            .flatten()
     }
 
-    pub async fn turn_on_spotlight(&self, duration: Duration) {
-        let mut write_lock = self.inner.write().await;
-        write_lock.hardware.turn_on_spotlight();
-        write_lock.instant_to_turn_off = Some(std::time::Instant::now() + duration);
-        let self_ref = self.new_ref_counted();
-        tokio::task::spawn(async move {
-            tokio::time::sleep(duration).await;
-            info!("Checking if its time to turn off spotlight");
-            self_ref.check_spotlight_should_be_turned_off().await;
-        });
+    pub async fn unlock_gate(&self, gate: usize) {
+        self.request(|done| Command::UnlockGate { gate, done })
+            .await;
+    }
+
+    pub async fn turn_off_spotlight(&self, light: usize) {
+        self.request(|done| Command::TurnOffSpotlight { light, done })
+            .await;
+    }
+
+    /// Keeps the spotlight on for `duration` from now, whether it was on or not.
+    pub async fn turn_on_spotlight(&self, light: usize, duration: Duration) {
+        self.request(|done| Command::TurnOnSpotlight {
+            light,
+            duration,
+            done,
+        })
+        .await;
+    }
+
+    /// Adds `extra` to the time the spotlight stays on, turning it on if it was off. Returns the
+    /// time it will now stay on.
+    pub async fn extend_spotlight(&self, light: usize, extra: Duration) -> Duration {
+        self.request(|remaining| Command::ExtendSpotlight {
+            light,
+            extra,
+            remaining,
+        })
+        .await
+        .unwrap_or_default()
     }
 }
+
 impl RawHardware for RealHardware {
-    fn unlock_gate(&mut self) {
-        self.gate
-            .set_value(1)
-            .expect(&format!("Could not set GATE_PIN_NUMBER {} to 1", GATE));
-        sleep(Duration::from_millis(500));
-        self.gate
-            .set_value(0)
-            .expect(&format!("Could not set GATE_PIN_NUMBER {} to 0", GATE));
+    fn unlock_gate(&mut self, gate: usize) {
+        let pin = &self.gates[gate];
+        pin.set_value(1)
+            .unwrap_or_else(|e| panic!("Could not set gate pin {} to 1: {}", pin.get_pin_num(), e));
+        sleep(GATE_PULSE);
+        pin.set_value(0)
+            .unwrap_or_else(|e| panic!("Could not set gate pin {} to 0: {}", pin.get_pin_num(), e));
+    }
+
+    fn turn_on_spotlight(&mut self, light: usize) {
+        let pin = &self.lights[light];
+        pin.set_value(1).unwrap_or_else(|e| {
+            panic!(
+                "Could not set spotlight pin {} to 1: {}",
+                pin.get_pin_num(),
+                e
+            )
+        });
+    }
+
+    fn turn_off_spotlight(&mut self, light: usize) {
+        let pin = &self.lights[light];
+        pin.set_value(0).unwrap_or_else(|e| {
+            panic!(
+                "Could not set spotlight pin {} to 0: {}",
+                pin.get_pin_num(),
+                e
+            )
+        });
     }
 
-    fn turn_on_spotlight(&mut self) {
-        self.spotlight
-            .set_value(1)
-            .expect(&format!("Could not set SPOTLIGHT_PIN {} to 0", SPOTLIGHT));
+    fn reset_outputs(&mut self) {
+        for pin in self.gates.iter().chain(&self.lights) {
+            if let Err(e) = pin.set_value(0) {
+                error!("Could not reset pin {} to 0: {}", pin.get_pin_num(), e);
+            }
+        }
     }
 
-    fn turn_off_spotlight(&mut self) {
-        self.spotlight
-            .set_value(0)
-            .expect(&format!("Could not set SPOTLIGHT_PIN {} to 1", SPOTLIGHT));
+    fn is_gate_closed(&mut self, gate: usize) -> Option<bool> {
+        let pin = self.gate_sensors[gate].as_ref()?;
+        match pin.get_value() {
+            Ok(value) => Some(value == 1),
+            Err(e) => {
+                error!(
+                    "Could not read gate sensor pin {}: {}",
+                    pin.get_pin_num(),
+                    e
+                );
+                None
+            }
+        }
     }
 }
 impl RawHardware for MockHardware {
-    fn unlock_gate(&mut self) {
-        sleep(Duration::from_millis(500));
-        info!("Unlocked");
+    fn unlock_gate(&mut self, gate: usize) {
+        sleep(GATE_PULSE);
+        info!("Unlocked gate {gate}");
     }
 
-    fn turn_on_spotlight(&mut self) {
+    fn turn_on_spotlight(&mut self, light: usize) {
         sleep(Duration::from_millis(500));
-        info!("Spotlight On");
+        info!("Spotlight {light} On");
     }
 
-    fn turn_off_spotlight(&mut self) {
+    fn turn_off_spotlight(&mut self, light: usize) {
         sleep(Duration::from_millis(500));
-        info!("Spotlight Off");
+        info!("Spotlight {light} Off");
+    }
+
+    fn reset_outputs(&mut self) {
+        info!("Outputs reset");
     }
 }
 
+/// Gates and lights are indexes into [HardwareConfig::gates] and [HardwareConfig::lights]. Called
+/// on the blocking pool, so methods may block. A panic resets the outputs and stops the hardware
+/// task for good, see [RefCountedGateHardware::watchdog].
 pub trait RawHardware: Send + Sync + 'static {
-    fn unlock_gate(&mut self);
+    fn unlock_gate(&mut self, gate: usize);
 
-    fn turn_on_spotlight(&mut self);
+    fn turn_on_spotlight(&mut self, light: usize);
 
-    fn turn_off_spotlight(&mut self);
+    fn turn_off_spotlight(&mut self, light: usize);
+
+    /// Drives every output low, must not panic since it is used to recover from failures.
+    fn reset_outputs(&mut self);
+
+    /// Reads the sensor telling whether the gate is closed, `None` when it has none.
+    fn is_gate_closed(&mut self, _gate: usize) -> Option<bool> {
+        None
+    }
 }
 
 impl RealHardware {
-    pub fn new() -> Self {
-        let gate = Pin::new(GATE);
-        gate.export()
-            .expect(&format!("Could not export pin {} to user space.", GATE));
-        sleep(Duration::from_millis(500));
-        gate.set_direction(Direction::Out)
-            .expect(&format!("Could not set pin {} direction to Out", GATE));
-        sleep(Duration::from_millis(500));
-        gate.set_value(0).expect(&format!(
-            "Could not set GATE_PIN_NUMBER {} to 0 on startup",
-            GATE
-        ));
-
-        let spotlight = Pin::new(SPOTLIGHT);
-        spotlight.export().expect(&format!(
-            "Could not export pin {} to user space.",
-            SPOTLIGHT
-        ));
+    pub fn new(gate_pins: &[u64], gate_sensor_pins: &[Option<u64>], light_pins: &[u64]) -> Self {
+        let gates = gate_pins.iter().copied().map(init_output_pin).collect();
+        let gate_sensors = gate_sensor_pins
+            .iter()
+            .map(|pin| pin.map(init_input_pin))
+            .collect();
+        let lights = light_pins.iter().copied().map(init_output_pin).collect();
+        RealHardware {
+            gates,
+            gate_sensors,
+            lights,
+        }
+    }
+}
+
+fn export_pin(number: u64) -> Pin {
+    let pin = Pin::new(number);
+    if pin.is_exported() {
+        warn!(
+            "Pin {} was already exported, probably from a previous crash. Reusing it.",
+            number
+        );
+    } else {
+        pin.export()
+            .unwrap_or_else(|e| panic!("Could not export pin {} to user space: {}", number, e));
         sleep(Duration::from_millis(500));
-        spotlight
-            .set_direction(Direction::Out)
-            .expect(&format!("Could not set pin {} direction to Out", SPOTLIGHT));
-        spotlight.set_value(0).expect(&format!(
-            "Could not set SPOTLIGHT_PIN {} to 0 on startup",
-            GATE
-        ));
-
-        RealHardware { gate, spotlight }
+    }
+    pin
+}
+
+fn init_input_pin(number: u64) -> Pin {
+    let pin = export_pin(number);
+    pin.set_direction(Direction::In)
+        .unwrap_or_else(|e| panic!("Could not set pin {} direction to In: {}", number, e));
+    pin
+}
+
+/// Exports the pin (if needed) and drives it low. If a previous run crashed without unexporting,
+/// the pin is reused as is instead of failing, since its value may still be high.
+fn init_output_pin(number: u64) -> Pin {
+    let pin = export_pin(number);
+    pin.set_direction(Direction::Out)
+        .unwrap_or_else(|e| panic!("Could not set pin {} direction to Out: {}", number, e));
+    pin.set_value(0)
+        .unwrap_or_else(|e| panic!("Could not set pin {} to 0 on startup: {}", number, e));
+    pin
+}
+
+impl Drop for RealHardware {
+    fn drop(&mut self) {
+        info!("Dropping hardware, setting outputs low and unexporting pins");
+        for pin in self.gates.iter().chain(&self.lights) {
+            if let Err(e) = pin.set_value(0) {
+                error!("Could not set pin {} to 0: {}", pin.get_pin_num(), e);
+            }
+            if let Err(e) = pin.unexport() {
+                error!("Could not unexport pin {}: {}", pin.get_pin_num(), e);
+            }
+        }
+        for pin in self.gate_sensors.iter().flatten() {
+            if let Err(e) = pin.unexport() {
+                error!("Could not unexport pin {}: {}", pin.get_pin_num(), e);
+            }
+        }
     }
 }
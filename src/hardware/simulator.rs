@@ -0,0 +1,269 @@
+use crate::hardware::{RawHardware, GATE_PULSE};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Output {
+    Gate(usize),
+    Light(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub at: Instant,
+    pub output: Output,
+    pub high: bool,
+}
+
+#[derive(Default)]
+struct Recording {
+    levels: HashMap<Output, bool>,
+    timeline: Vec<Transition>,
+    gates_closed: HashMap<usize, bool>,
+    broken: bool,
+}
+
+/// Records every output transition, with the tokio clock so tests can pause it, instead of
+/// driving pins. Clones share the recording, so tests keep one while the hardware task owns
+/// another.
+#[derive(Clone, Default)]
+pub struct SimulatedHardware {
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl SimulatedHardware {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn recording(&self) -> std::sync::MutexGuard<'_, Recording> {
+        self.recording.lock().expect("recording lock poisoned")
+    }
+
+    /// Only levels that change are recorded, like a logic analyzer would see them. Transitions
+    /// recorded ahead of time, like the end of a gate pulse, are kept in order of `at`.
+    fn set(&self, output: Output, high: bool, at: Instant) {
+        let mut recording = self.recording();
+        if recording.levels.get(&output).copied().unwrap_or(false) == high {
+            return;
+        }
+        recording.levels.insert(output, high);
+        let position = recording
+            .timeline
+            .partition_point(|transition| transition.at <= at);
+        recording
+            .timeline
+            .insert(position, Transition { at, output, high });
+    }
+
+    pub fn timeline(&self) -> Vec<Transition> {
+        self.recording().timeline.clone()
+    }
+
+    pub fn transitions(&self, output: Output) -> Vec<Transition> {
+        self.recording()
+            .timeline
+            .iter()
+            .filter(|transition| transition.output == output)
+            .copied()
+            .collect()
+    }
+
+    pub fn is_high(&self, output: Output) -> bool {
+        self.recording()
+            .levels
+            .get(&output)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Makes driving an output panic from now on, like [RealHardware] does when it can't write a
+    /// pin. Resetting the outputs still works.
+    ///
+    /// [RealHardware]: crate::hardware::RealHardware
+    pub fn break_outputs(&self) {
+        self.recording().broken = true;
+    }
+
+    fn check_outputs(&self) {
+        if self.recording().broken {
+            panic!("simulated output failure");
+        }
+    }
+
+    /// What the gate's closed sensor reads from now on.
+    pub fn set_gate_closed(&self, gate: usize, closed: bool) {
+        self.recording().gates_closed.insert(gate, closed);
+    }
+}
+
+impl RawHardware for SimulatedHardware {
+    /// The relay is released [GATE_PULSE] later without blocking, so the clock can stay paused.
+    /// Its level reads low right away, only the timeline shows the pulse.
+    fn unlock_gate(&mut self, gate: usize) {
+        self.check_outputs();
+        let now = Instant::now();
+        self.set(Output::Gate(gate), true, now);
+        self.set(Output::Gate(gate), false, now + GATE_PULSE);
+    }
+
+    fn turn_on_spotlight(&mut self, light: usize) {
+        self.check_outputs();
+        self.set(Output::Light(light), true, Instant::now());
+    }
+
+    fn turn_off_spotlight(&mut self, light: usize) {
+        self.check_outputs();
+        self.set(Output::Light(light), false, Instant::now());
+    }
+
+    fn reset_outputs(&mut self) {
+        let now = Instant::now();
+        let outputs: Vec<Output> = self.recording().levels.keys().copied().collect();
+        for output in outputs {
+            self.set(output, false, now);
+        }
+    }
+
+    fn is_gate_closed(&mut self, gate: usize) -> Option<bool> {
+        self.recording().gates_closed.get(&gate).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::RefCountedGateHardware;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    fn spawn(lights: usize) -> (RefCountedGateHardware<SimulatedHardware>, SimulatedHardware) {
+        let simulator = SimulatedHardware::new();
+        let hw = RefCountedGateHardware::spawn(simulator.clone(), lights);
+        (hw, simulator)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlocking_pulses_the_gate_relay() {
+        let (hw, simulator) = spawn(1);
+        let start = Instant::now();
+        hw.unlock_gate(0).await;
+        let pulse = simulator.transitions(Output::Gate(0));
+        assert_eq!(pulse.len(), 2);
+        assert!(pulse[0].high && !pulse[1].high);
+        assert_eq!(pulse[0].at, start);
+        assert_eq!(pulse[1].at - pulse[0].at, GATE_PULSE);
+        assert!(simulator.transitions(Output::Light(0)).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeline_is_in_order_across_a_gate_pulse() {
+        let (hw, simulator) = spawn(1);
+        let start = Instant::now();
+        hw.unlock_gate(0).await;
+        hw.turn_on_spotlight(0, Duration::from_secs(60)).await;
+        let timeline: Vec<(Output, bool, Duration)> = simulator
+            .timeline()
+            .iter()
+            .map(|transition| (transition.output, transition.high, transition.at - start))
+            .collect();
+        assert_eq!(
+            timeline,
+            vec![
+                (Output::Gate(0), true, Duration::ZERO),
+                (Output::Light(0), true, Duration::ZERO),
+                (Output::Gate(0), false, GATE_PULSE),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spotlight_turns_off_when_its_time_is_up() {
+        let (hw, simulator) = spawn(1);
+        let start = Instant::now();
+        hw.turn_on_spotlight(0, Duration::from_secs(180)).await;
+        tokio::time::sleep(Duration::from_secs(179)).await;
+        assert!(simulator.is_high(Output::Light(0)));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(!simulator.is_high(Output::Light(0)));
+        assert!(!hw.is_spotlight_on(0));
+        let off = simulator.transitions(Output::Light(0))[1];
+        assert_eq!(off.at - start, Duration::from_secs(180));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn extending_moves_the_turn_off() {
+        let (hw, simulator) = spawn(2);
+        let start = Instant::now();
+        hw.turn_on_spotlight(0, Duration::from_secs(60)).await;
+        hw.turn_on_spotlight(1, Duration::from_secs(30)).await;
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        let remaining = hw.extend_spotlight(1, Duration::from_secs(60)).await;
+        assert_eq!(remaining, Duration::from_secs(70));
+        tokio::time::sleep(Duration::from_secs(100)).await;
+        let offs: Vec<(Output, Duration)> = simulator
+            .timeline()
+            .iter()
+            .filter(|transition| !transition.high)
+            .map(|transition| (transition.output, transition.at - start))
+            .collect();
+        assert_eq!(
+            offs,
+            vec![
+                (Output::Light(0), Duration::from_secs(60)),
+                (Output::Light(1), Duration::from_secs(90)),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn turning_off_cancels_the_timer() {
+        let (hw, simulator) = spawn(1);
+        hw.turn_on_spotlight(0, Duration::from_secs(60)).await;
+        hw.turn_off_spotlight(0).await;
+        hw.extend_spotlight(0, Duration::from_secs(10)).await;
+        tokio::time::sleep(Duration::from_secs(120)).await;
+        let levels: Vec<bool> = simulator
+            .transitions(Output::Light(0))
+            .iter()
+            .map(|transition| transition.high)
+            .collect();
+        assert_eq!(levels, vec![true, false, true, false]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn panicking_resets_the_outputs_and_stops_the_hardware_task() {
+        let (hw, simulator) = spawn(1);
+        hw.turn_on_spotlight(0, Duration::from_secs(60)).await;
+        simulator.break_outputs();
+        hw.unlock_gate(0).await;
+        assert!(simulator.transitions(Output::Gate(0)).is_empty());
+        assert!(!simulator.is_high(Output::Light(0)));
+        assert!(!hw.is_spotlight_on(0));
+        assert!(!hw.is_responsive().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watchdog_stops_the_controller_once_the_hardware_task_dies() {
+        let (hw, simulator) = spawn(1);
+        let (alive, stopped) = oneshot::channel::<()>();
+        let controller = tokio::spawn(async move {
+            let _alive = alive;
+            std::future::pending::<()>().await
+        });
+        simulator.break_outputs();
+        tokio::join!(hw.watchdog(controller), hw.unlock_gate(0));
+        assert!(stopped.await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_injected_gate_sensor() {
+        let (hw, simulator) = spawn(0);
+        assert_eq!(hw.is_gate_closed(0).await, None);
+        simulator.set_gate_closed(0, true);
+        assert_eq!(hw.is_gate_closed(0).await, Some(true));
+        simulator.set_gate_closed(0, false);
+        assert_eq!(hw.is_gate_closed(0).await, Some(false));
+    }
+}
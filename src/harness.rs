@@ -0,0 +1,167 @@
+use crate::bot::callback::CallbackSigner;
+use crate::config::{Config, DatabaseKind, GateConfig, LightConfig, NightApprovalConfig};
+use crate::database::{Database, DbUser, MemoryStore, UserRole};
+use crate::hardware::simulator::SimulatedHardware;
+use crate::hardware::RefCountedGateHardware;
+use crate::health::Heartbeat;
+use crate::pending::PendingActions;
+use crate::rate_limit::RateLimiter;
+use crate::telegram::fake::FakeTelegram;
+use crate::telegram::{OutgoingMessage, TelegramInterface};
+use crate::State;
+use chrono::Timelike;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, RwLock};
+
+pub const ADMIN: i64 = 1;
+pub const RESIDENT: i64 = 2;
+pub const GUEST: i64 = 3;
+/// Not in the store.
+pub const STRANGER: i64 = 99;
+
+/// The bot wired to [FakeTelegram], [MemoryStore] and [SimulatedHardware], handling updates like
+/// `main` does. Meant for tests with the tokio clock paused, so timers can be skipped through.
+pub struct Harness {
+    pub state: Arc<State<SimulatedHardware>>,
+    pub telegram: FakeTelegram,
+    pub hardware: SimulatedHardware,
+    pub store: MemoryStore,
+}
+
+impl Harness {
+    /// See [config].
+    pub async fn start() -> Harness {
+        Self::with_config(config()).await
+    }
+
+    /// The store starts with the config's `database.users`. The config has to pass validation, so
+    /// tests don't exercise settings that can't happen.
+    pub async fn with_config(config: Config) -> Harness {
+        config.validate().expect("invalid test config");
+        let hardware = SimulatedHardware::new();
+        let hw = RefCountedGateHardware::spawn(hardware.clone(), config.hardware.lights.len());
+        let store = MemoryStore::new(config.database.users.clone());
+        let db = Database::new(store.clone());
+        db.refresh_users().await.expect("memory store works");
+        let mut telegram = FakeTelegram::new();
+        let receiver = telegram.start_getting_updates();
+        let state = Arc::new(State {
+            hw,
+            pending_actions: PendingActions::default(),
+            rate_limiter: RateLimiter::new(config.rate_limit.clone()),
+            callback_signer: CallbackSigner::random(),
+            db,
+            poller_heartbeat: Heartbeat::default(),
+            config: RwLock::new(config),
+            schedules_changed: Notify::new(),
+        });
+        tokio::spawn(crate::handle_updates(
+            Arc::clone(&state),
+            telegram.clone(),
+            receiver,
+        ));
+        Harness {
+            state,
+            telegram,
+            hardware,
+            store,
+        }
+    }
+
+    /// Lets the bot handle everything sent so far. With the clock paused, time only moves on
+    /// once every task is waiting, so this advances it by a millisecond at most.
+    pub async fn settle(&self) {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    /// Returns the id of the user's message.
+    pub async fn send_text(&self, user_id: i64, text: &str) -> i32 {
+        let message_id = self.telegram.send_text(user_id, text).await;
+        self.settle().await;
+        message_id
+    }
+
+    /// Returns the callback query id.
+    pub async fn press(&self, user_id: i64, callback_data: &str) -> String {
+        let callback_query_id = self.telegram.press(user_id, callback_data).await;
+        self.settle().await;
+        callback_query_id
+    }
+
+    /// Presses the button with the label in the last message sent to the user.
+    pub async fn press_button(&self, user_id: i64, label: &str) -> String {
+        let callback_data = self
+            .last_message_to(user_id)
+            .buttons
+            .into_iter()
+            .flatten()
+            .find(|button| button.label == label)
+            .unwrap_or_else(|| panic!("no {label:?} button for {user_id}"))
+            .callback_data;
+        self.press(user_id, &callback_data).await
+    }
+
+    pub fn last_message_to(&self, user_id: i64) -> OutgoingMessage {
+        self.telegram
+            .last_message_to(user_id)
+            .unwrap_or_else(|| panic!("nothing sent to {user_id}"))
+    }
+
+    pub fn answer_to(&self, callback_query_id: &str) -> String {
+        self.telegram
+            .answer_to(callback_query_id)
+            .unwrap_or_else(|| panic!("callback query {callback_query_id} not answered"))
+    }
+}
+
+/// One gate and one light, with an admin, a resident and a guest in the memory database.
+pub fn config() -> Config {
+    let mut config = Config::default();
+    config.database.kind = DatabaseKind::Memory;
+    config.database.users = vec![
+        user(ADMIN, "Admin", UserRole::Admin),
+        user(RESIDENT, "Resident", UserRole::Resident),
+        user(GUEST, "Guest", UserRole::Guest),
+    ];
+    config.telegram.bot_token = "test".to_string();
+    config.hardware.gates = vec![GateConfig {
+        id: "gate".to_string(),
+        name: "Portão".to_string(),
+        pin: 26,
+        sensor_pin: None,
+    }];
+    config.hardware.lights = vec![LightConfig {
+        id: "spotlight".to_string(),
+        name: "Luz".to_string(),
+        pin: 17,
+        duration_secs: None,
+    }];
+    config
+}
+
+/// Residents need an approval from the current local hour until the one before it, so it applies
+/// whenever tests run.
+pub fn config_with_night_approval() -> Config {
+    let mut config = config();
+    let local_hour = (chrono::Utc::now()
+        + chrono::Duration::hours(config.logging.utc_offset_hours.into()))
+    .hour();
+    config.bot.night_approval = Some(NightApprovalConfig {
+        from_hour: local_hour,
+        until_hour: (local_hour + 23) % 24,
+        roles: vec![UserRole::Resident],
+        timeout_secs: 60,
+    });
+    config
+}
+
+pub fn user(telegram_id: i64, name: &str, role: UserRole) -> DbUser {
+    DbUser {
+        telegram_id,
+        name: name.to_string(),
+        role,
+        locale: Some("pt-BR".to_string()),
+        devices: None,
+    }
+}
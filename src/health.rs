@@ -0,0 +1,111 @@
+use crate::hardware::RawHardware;
+use crate::State;
+use axum::extract::State as AxumState;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use sd_notify::NotifyState;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// Telegram long polling waits up to 30s, and on errors the poller sleeps for 30s before trying
+/// again, so anything longer than this between poll attempts means it is stuck.
+const POLLER_MAX_SILENCE: Duration = Duration::from_secs(90);
+
+/// Last time a background task proved it is making progress.
+#[derive(Clone)]
+pub struct Heartbeat(Arc<watch::Sender<Option<Instant>>>);
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(None)))
+    }
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.0.send_replace(Some(Instant::now()));
+    }
+
+    /// Not alive until the first beat.
+    pub fn is_alive(&self, max_silence: Duration) -> bool {
+        self.0
+            .borrow()
+            .is_some_and(|last_beat| last_beat.elapsed() < max_silence)
+    }
+
+    pub async fn first_beat(&self) {
+        // the sender lives in self, so the channel can't close
+        let _ = self.0.subscribe().wait_for(Option::is_some).await;
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    telegram_poller: bool,
+    database: bool,
+    hardware: bool,
+}
+
+pub fn router<T: RawHardware>() -> Router<Arc<State<T>>> {
+    Router::new()
+        .route("/health/live", get(live::<T>))
+        .route("/health/ready", get(ready::<T>))
+}
+
+async fn live<T: RawHardware>(AxumState(state): AxumState<Arc<State<T>>>) -> StatusCode {
+    if state.poller_heartbeat.is_alive(POLLER_MAX_SILENCE) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+async fn ready<T: RawHardware>(
+    AxumState(state): AxumState<Arc<State<T>>>,
+) -> (StatusCode, Json<Readiness>) {
+    let readiness = Readiness {
+        telegram_poller: state.poller_heartbeat.is_alive(POLLER_MAX_SILENCE),
+        database: state.db.ping().await.is_ok(),
+        hardware: tokio::time::timeout(Duration::from_secs(2), state.hw.is_responsive())
+            .await
+            .unwrap_or(false),
+    };
+    let status = if readiness.telegram_poller && readiness.database && readiness.hardware {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+/// Tells systemd we are ready once the Telegram poller finished its first poll and, if
+/// `WatchdogSec` is configured, keeps pinging the watchdog only while the poller keeps polling, so
+/// systemd restarts us when it gets stuck. Polls failing don't count as stuck, since restarting
+/// won't bring the internet back and would take down the HTTP API and MQTT with it.
+pub async fn notify_systemd(poller_heartbeat: Heartbeat) {
+    poller_heartbeat.first_beat().await;
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!("Notifying systemd: {e}");
+    }
+    let mut watchdog_usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut watchdog_usec) {
+        info!("systemd watchdog not enabled");
+        return;
+    }
+    let period = Duration::from_micros(watchdog_usec) / 2;
+    info!("Pinging systemd watchdog every {period:?}");
+    loop {
+        tokio::time::sleep(period).await;
+        if poller_heartbeat.is_alive(POLLER_MAX_SILENCE) {
+            if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                warn!("Pinging systemd watchdog: {e}");
+            }
+        } else {
+            error!("Telegram poller stalled, not pinging systemd watchdog");
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use std::time::Duration;
+
+/// Languages the bot can talk in, each method below is the catalog entry for one message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    PtBr,
+    En,
+}
+
+impl Locale {
+    /// Accepts both Telegram's `language_code` (IETF tags like "pt-br" or "en") and the codes
+    /// stored in `users.locale`.
+    pub fn from_code(code: &str) -> Option<Locale> {
+        let language = code.split(['-', '_']).next()?.to_lowercase();
+        match language.as_str() {
+            "pt" => Some(Locale::PtBr),
+            "en" => Some(Locale::En),
+            _ => None,
+        }
+    }
+
+    /// The user's saved locale wins over the language of their Telegram client.
+    pub fn choose(user_locale: Option<&str>, telegram_language_code: Option<&str>) -> Locale {
+        user_locale
+            .and_then(Locale::from_code)
+            .or_else(|| telegram_language_code.and_then(Locale::from_code))
+            .unwrap_or_default()
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Locale::PtBr => "pt-BR",
+            Locale::En => "en",
+        }
+    }
+
+    pub fn open_button(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Abrir",
+            Locale::En => "Open",
+        }
+    }
+
+    pub fn light_button(self, minutes: u64) -> String {
+        match self {
+            Locale::PtBr => format!("Luz {minutes}min"),
+            Locale::En => format!("Light {minutes}min"),
+        }
+    }
+
+    pub fn open_gate_button(self, gate: &str) -> String {
+        match self {
+            Locale::PtBr => format!("Abrir {gate}"),
+            Locale::En => format!("Open {gate}"),
+        }
+    }
+
+    pub fn named_light_button(self, light: &str, minutes: u64) -> String {
+        match self {
+            Locale::PtBr => format!("Luz {light} {minutes}min"),
+            Locale::En => format!("Light {light} {minutes}min"),
+        }
+    }
+
+    pub fn not_permitted(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Você não tem permissão para isso",
+            Locale::En => "You are not allowed to do that",
+        }
+    }
+
+    pub fn confirm_open_button(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Confirmar Abrir",
+            Locale::En => "Confirm Open",
+        }
+    }
+
+    pub fn confirm_open_question(self, gate: &str, disappears_in_secs: u64) -> String {
+        match self {
+            Locale::PtBr => format!(
+                "Você realmente quer abrir {gate}? Essa messagem vai desaparecer em {disappears_in_secs}s"
+            ),
+            Locale::En => format!(
+                "Do you really want to open {gate}? This message will disappear in {disappears_in_secs}s"
+            ),
+        }
+    }
+
+    pub fn check_before_opening(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Veja quem está na porta antes de abrir!",
+            Locale::En => "Check who is at the gate before opening!",
+        }
+    }
+
+    pub fn gate_unlocked(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Aberto!",
+            Locale::En => "Opened!",
+        }
+    }
+
+    pub fn light_turned_on(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Luz Ligada",
+            Locale::En => "Light on",
+        }
+    }
+
+    pub fn light_already_on(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Já está ligada",
+            Locale::En => "It is already on",
+        }
+    }
+
+    pub fn light_extended(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Tempo aumentado",
+            Locale::En => "Time extended",
+        }
+    }
+
+    pub fn light_turned_off(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Luz Desligada",
+            Locale::En => "Light off",
+        }
+    }
+
+    pub fn light_remaining(self, light: &str, remaining: Duration) -> String {
+        let (minutes, seconds) = (remaining.as_secs() / 60, remaining.as_secs() % 60);
+        match self {
+            Locale::PtBr => format!("{light}: ligada por mais {minutes}min {seconds:02}s"),
+            Locale::En => format!("{light}: on for {minutes}min {seconds:02}s more"),
+        }
+    }
+
+    pub fn extend_light_button(self, minutes: u64) -> String {
+        format!("+{minutes} min")
+    }
+
+    pub fn turn_off_light_button(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Desligar",
+            Locale::En => "Turn off",
+        }
+    }
+
+    pub fn light_minutes_button(self, minutes: u64) -> String {
+        format!("{minutes} min")
+    }
+
+    pub fn waiting_for_approval(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Pedido enviado aos moradores, aguarde a aprovação",
+            Locale::En => "Request sent to the residents, waiting for approval",
+        }
+    }
+
+    pub fn no_approvers(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Não há moradores para aprovar a abertura",
+            Locale::En => "There are no residents to approve the opening",
+        }
+    }
+
+    pub fn approval_request(self, requester: &str, gate: &str, timeout_secs: u64) -> String {
+        match self {
+            Locale::PtBr => format!(
+                "{requester} quer abrir: {gate}. Aprovar? Essa mensagem vai desaparecer em {timeout_secs}s"
+            ),
+            Locale::En => format!(
+                "{requester} wants to open: {gate}. Approve? This message will disappear in {timeout_secs}s"
+            ),
+        }
+    }
+
+    pub fn approve_button(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Aprovar",
+            Locale::En => "Approve",
+        }
+    }
+
+    pub fn deny_button(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Negar",
+            Locale::En => "Deny",
+        }
+    }
+
+    pub fn approved_by(self, approver: &str) -> String {
+        match self {
+            Locale::PtBr => format!("Aprovado por {approver}, aberto!"),
+            Locale::En => format!("Approved by {approver}, opened!"),
+        }
+    }
+
+    /// The gate or the permission to open it went away while waiting for the approval.
+    pub fn approved_but_not_opened(self, approver: &str) -> String {
+        match self {
+            Locale::PtBr => format!("Aprovado por {approver}, mas não foi possível abrir"),
+            Locale::En => format!("Approved by {approver}, but it could not be opened"),
+        }
+    }
+
+    pub fn denied_by(self, approver: &str) -> String {
+        match self {
+            Locale::PtBr => format!("Negado por {approver}"),
+            Locale::En => format!("Denied by {approver}"),
+        }
+    }
+
+    pub fn approval_timed_out(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Ninguém aprovou a tempo",
+            Locale::En => "Nobody approved in time",
+        }
+    }
+
+    pub fn approval_answered(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Resposta registrada",
+            Locale::En => "Answer recorded",
+        }
+    }
+
+    pub fn approval_already_answered(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Esse pedido já foi respondido ou expirou",
+            Locale::En => "This request was already answered or expired",
+        }
+    }
+
+    pub fn enter_pin(self, gate: &str, disappears_in_secs: u64) -> String {
+        match self {
+            Locale::PtBr => format!(
+                "Digite seu PIN para abrir {gate}. Essa messagem vai desaparecer em {disappears_in_secs}s"
+            ),
+            Locale::En => format!(
+                "Type your PIN to open {gate}. This message will disappear in {disappears_in_secs}s"
+            ),
+        }
+    }
+
+    pub fn wrong_pin(self) -> &'static str {
+        match self {
+            Locale::PtBr => "PIN incorreto",
+            Locale::En => "Wrong PIN",
+        }
+    }
+
+    pub fn pin_locked(self, minutes: i64) -> String {
+        match self {
+            Locale::PtBr => format!("Muitas tentativas erradas, tente novamente em {minutes}min"),
+            Locale::En => format!("Too many wrong attempts, try again in {minutes}min"),
+        }
+    }
+
+    pub fn pin_changed(self) -> &'static str {
+        match self {
+            Locale::PtBr => "PIN alterado, apague a mensagem com ele",
+            Locale::En => "PIN changed, delete the message containing it",
+        }
+    }
+
+    pub fn pin_removed(self) -> &'static str {
+        match self {
+            Locale::PtBr => "PIN removido",
+            Locale::En => "PIN removed",
+        }
+    }
+
+    pub fn pin_usage(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Use /pin NOVO (ou /pin ATUAL NOVO para trocar, /pin ATUAL off para remover), com pelo menos 4 caracteres",
+            Locale::En => "Use /pin NEW (or /pin CURRENT NEW to change it, /pin CURRENT off to remove it), with at least 4 characters",
+        }
+    }
+
+    pub fn schedule_usage(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Use /schedule add MIN HORA DIA MÊS DIA_DA_SEMANA open PORTÃO (ou light LUZ MINUTOS), /schedule list ou /schedule remove ID. Exemplo: /schedule add 30 7 * * 1-5 open gate",
+            Locale::En => "Use /schedule add MIN HOUR DAY MONTH WEEKDAY open GATE (or light LIGHT MINUTES), /schedule list or /schedule remove ID. Example: /schedule add 30 7 * * 1-5 open gate",
+        }
+    }
+
+    pub fn invalid_schedule(self, error: &str) -> String {
+        match self {
+            Locale::PtBr => format!("Agendamento inválido: {error}"),
+            Locale::En => format!("Invalid schedule: {error}"),
+        }
+    }
+
+    pub fn schedule_added(self, id: i32) -> String {
+        match self {
+            Locale::PtBr => format!("Agendamento #{id} criado"),
+            Locale::En => format!("Schedule #{id} added"),
+        }
+    }
+
+    pub fn schedule_removed(self, id: i32) -> String {
+        match self {
+            Locale::PtBr => format!("Agendamento #{id} removido"),
+            Locale::En => format!("Schedule #{id} removed"),
+        }
+    }
+
+    pub fn schedule_not_found(self, id: i32) -> String {
+        match self {
+            Locale::PtBr => format!("Não existe agendamento #{id}"),
+            Locale::En => format!("There is no schedule #{id}"),
+        }
+    }
+
+    pub fn no_schedules(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Nenhum agendamento",
+            Locale::En => "No schedules",
+        }
+    }
+
+    pub fn rate_limited(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Muitas requisições, aguarde um pouco",
+            Locale::En => "Too many requests, wait a bit",
+        }
+    }
+
+    pub fn database_unavailable(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Sistema fora do ar, tente de novo em alguns minutos",
+            Locale::En => "The system is down, try again in a few minutes",
+        }
+    }
+
+    pub fn request_failed(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Algo deu errado, tente de novo",
+            Locale::En => "Something went wrong, try again",
+        }
+    }
+
+    pub fn unknown_user_banned(self, user_id: i64, minutes: u64) -> String {
+        match self {
+            Locale::PtBr => format!(
+                "O usuário desconhecido {user_id} está enviando muitas mensagens e foi bloqueado por {minutes}min"
+            ),
+            Locale::En => format!(
+                "Unknown user {user_id} is sending too many messages and was blocked for {minutes}min"
+            ),
+        }
+    }
+
+    pub fn stranger_attempt(
+        self,
+        user_id: i64,
+        username: Option<&str>,
+        name: &str,
+        content: &str,
+    ) -> String {
+        let username = username
+            .map(|username| format!(" @{username}"))
+            .unwrap_or_default();
+        match self {
+            Locale::PtBr => format!(
+                "Tentativa de acesso de quem não está cadastrado:\n{name}{username} (id {user_id})\n{content}"
+            ),
+            Locale::En => format!(
+                "Access attempt by someone who is not registered:\n{name}{username} (id {user_id})\n{content}"
+            ),
+        }
+    }
+
+    pub fn stranger_pressed_button(self) -> &'static str {
+        match self {
+            Locale::PtBr => "(apertou um botão)",
+            Locale::En => "(pressed a button)",
+        }
+    }
+
+    pub fn grant_guest_button(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Dar acesso de convidado",
+            Locale::En => "Grant guest access",
+        }
+    }
+
+    pub fn block_button(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Bloquear",
+            Locale::En => "Block",
+        }
+    }
+
+    pub fn guest_access_granted(self, name: &str) -> String {
+        match self {
+            Locale::PtBr => format!("{name} agora é convidado"),
+            Locale::En => format!("{name} is now a guest"),
+        }
+    }
+
+    pub fn stranger_blocked(self, name: &str) -> String {
+        match self {
+            Locale::PtBr => format!("{name} foi bloqueado"),
+            Locale::En => format!("{name} was blocked"),
+        }
+    }
+
+    pub fn welcome_guest(self) -> &'static str {
+        match self {
+            Locale::PtBr => {
+                "Você recebeu acesso de convidado. Veja quem está na porta antes de abrir!"
+            }
+            Locale::En => "You were granted guest access. Check who is at the gate before opening!",
+        }
+    }
+
+    pub fn reloaded(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Configuração recarregada",
+            Locale::En => "Configuration reloaded",
+        }
+    }
+
+    pub fn reload_failed(self, error: &str) -> String {
+        match self {
+            Locale::PtBr => format!("Erro ao recarregar: {error}"),
+            Locale::En => format!("Reloading failed: {error}"),
+        }
+    }
+
+    pub fn locale_changed(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Idioma alterado",
+            Locale::En => "Language changed",
+        }
+    }
+
+    pub fn unknown_locale(self) -> &'static str {
+        match self {
+            Locale::PtBr => "Idioma desconhecido, use /language pt ou /language en",
+            Locale::En => "Unknown language, use /language pt or /language en",
+        }
+    }
+
+    pub fn unauthorized(self, user_id: i64) -> String {
+        match self {
+            Locale::PtBr => format!(
+                "Você não está cadastrado. Envie essa mensagem para @TiberioFerreira com seu id: {user_id}"
+            ),
+            Locale::En => format!(
+                "You are not registered. Send this message to @TiberioFerreira with your id: {user_id}"
+            ),
+        }
+    }
+}
@@ -1,30 +1,51 @@
+mod api;
+mod automation;
 mod bot;
+mod config;
+mod cron;
 mod database;
 mod hardware;
+#[cfg(test)]
+mod harness;
+mod health;
+mod i18n;
+mod metrics;
+mod mqtt;
+mod pending;
+mod rate_limit;
+mod sun;
 
+use crate::bot::callback::CallbackSigner;
 use crate::bot::handle_update;
-use crate::database::Database;
+use crate::config::{Config, DatabaseConfig, DatabaseKind, HardwareKind, LoggingConfig};
+use crate::database::{Database, MemoryStore, PostgresStore};
 use crate::hardware::{MockHardware, RawHardware, RealHardware, RefCountedGateHardware};
-use crate::telegram::{FrankensteinWrapper, TelegramInterface};
+use crate::health::Heartbeat;
+use crate::pending::{PendingAction, PendingActions};
+use crate::rate_limit::RateLimiter;
+use crate::telegram::{FrankensteinWrapper, Outbox, TelegramInterface, TelegramUpdate};
 use sqlx::postgres::PgPoolOptions;
-use std::collections::HashMap;
+use std::process::ExitCode;
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::RwLock;
-use tracing::{error, info};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{Notify, RwLock};
+use tracing::{debug, error, info, warn};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::{fmt::time::OffsetTime, EnvFilter};
 
 mod telegram;
 
-fn start_logging() -> WorkerGuard {
-    let offset = time::UtcOffset::from_hms(3, 0, 0).expect("offset should work");
+fn start_logging(config: &LoggingConfig) -> WorkerGuard {
+    let offset =
+        time::UtcOffset::from_hms(config.utc_offset_hours, 0, 0).expect("offset should work");
     let time_format =
         time::format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]")
             .expect("format string should be valid!");
     let timer = OffsetTime::new(offset, time_format);
-    let file_appender = RollingFileAppender::new(Rotation::DAILY, "./logs", "log");
+    let file_appender = RollingFileAppender::new(Rotation::DAILY, &config.directory, "log");
     let (non_blocking, logs_flush_guard) = tracing_appender::non_blocking(file_appender);
     let subscriber = tracing_subscriber::fmt()
         .with_timer(timer)
@@ -38,43 +59,179 @@ fn start_logging() -> WorkerGuard {
 
 pub struct State<T: RawHardware> {
     pub hw: RefCountedGateHardware<T>,
-    pub open_requests_waiting_confirmation: RwLock<HashMap<i64, Instant>>,
+    pub pending_actions: PendingActions<PendingAction>,
+    pub rate_limiter: RateLimiter,
+    pub callback_signer: CallbackSigner,
     pub db: Database,
+    pub poller_heartbeat: Heartbeat,
+    pub config: RwLock<Config>,
+    /// Wakes the scheduler up to load the schedules again after they were changed.
+    pub schedules_changed: Notify,
 }
 
-#[tokio::main]
-async fn main() {
+impl<T: RawHardware> State<T> {
+    /// Reloads the config file and the user cache. The Telegram poller and the hardware, along
+    /// with any spotlight timer, are left untouched.
+    pub async fn reload(&self) -> Result<(), String> {
+        let new_config = Config::load().map_err(|e| e.to_string())?;
+        let needs_restart = self.config.write().await.apply_reload(new_config);
+        if !needs_restart.is_empty() {
+            warn!(
+                "Changes to {} only take effect after a restart",
+                needs_restart.join(", ")
+            );
+        }
+        let users = self.db.refresh_users().await.map_err(|e| e.to_string())?;
+        info!("Reloaded config and {users} users");
+        Ok(())
+    }
+}
+
+async fn reload_on_sighup<T: RawHardware>(state: Arc<State<T>>) {
+    let mut hangups = signal(SignalKind::hangup()).expect("Could not listen to SIGHUP");
+    while hangups.recv().await.is_some() {
+        info!("Got SIGHUP, reloading");
+        if let Err(e) = state.reload().await {
+            error!("Reloading: {e}");
+        }
+    }
+}
+
+/// Drops expired pending actions, full rate limit buckets and finished bans.
+async fn sweep_expired<T: RawHardware>(state: Arc<State<T>>) {
+    let mut interval = tokio::time::interval(pending::SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let swept = state.pending_actions.sweep();
+        if swept > 0 {
+            debug!("Dropped {swept} expired pending actions");
+        }
+        state.rate_limiter.sweep();
+    }
+}
+
+/// Handles the updates one at a time, queueing the responses in the [Outbox].
+async fn handle_updates<T: RawHardware, I: TelegramInterface + Send + Sync + 'static>(
+    state: Arc<State<T>>,
+    telegram: I,
+    mut receiver: Receiver<Vec<TelegramUpdate>>,
+) {
+    let outbox = Outbox::spawn(telegram);
+    loop {
+        let updates = receiver.recv().await.expect("telegram disconnected!");
+        for update in updates {
+            let maybe_response = handle_update(Arc::clone(&state), &outbox, update).await;
+            if let Some(response) = maybe_response {
+                outbox.send(response);
+            }
+        }
+    }
+}
+
+/// How long the tasks get to stop when exiting, blocking ones like PIN hashing can't be cancelled.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn main() -> ExitCode {
     dotenv::dotenv().ok();
-    let _guard = start_logging();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let _guard = start_logging(&config.logging);
     info!("Starting Up!");
     let old_hook = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |panic| {
         error!("{}", panic.to_string());
         old_hook(panic);
     }));
-    info!("Connecting to the DB");
-    let pg_db_pool = PgPoolOptions::new()
-        .max_connections(3)
-        .connect(&std::env::var("DATABASE_URL").unwrap())
-        .await
-        .expect("Error connecting to the DB");
-    info!("Connected!");
-    let db = Database::new(pg_db_pool);
-    let mut fk = FrankensteinWrapper::new();
-    let mut receiver = fk.start_getting_updates();
+    let runtime = tokio::runtime::Runtime::new().expect("Could not start the tokio runtime");
+    let result = runtime.block_on(async {
+        match config.hardware.kind {
+            HardwareKind::Mock => {
+                let hw = RefCountedGateHardware::<MockHardware>::new_mock(&config.hardware);
+                run(hw, config).await
+            }
+            HardwareKind::Real => {
+                let hw =
+                    RefCountedGateHardware::<RealHardware>::new_real_hardware(&config.hardware);
+                run(hw, config).await
+            }
+        }
+    });
+    // drops every task, so the hardware task drops the hardware, which unexports the pins
+    runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("Exiting after {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn open_database(config: &DatabaseConfig) -> Database {
+    match config.kind {
+        DatabaseKind::Postgres => {
+            info!("Connecting to the DB");
+            let pg_db_pool = PgPoolOptions::new()
+                .max_connections(config.max_connections)
+                .connect(&config.url)
+                .await
+                .expect("Error connecting to the DB");
+            info!("Connected!");
+            sqlx::migrate!()
+                .run(&pg_db_pool)
+                .await
+                .expect("Error running DB migrations");
+            Database::new(PostgresStore::new(pg_db_pool))
+        }
+        DatabaseKind::Memory => {
+            warn!("Using the memory database, changes are lost on restart");
+            Database::new(MemoryStore::new(config.users.clone()))
+        }
+    }
+}
+
+/// Only returns if the controller fails.
+async fn run<T: RawHardware>(hw: RefCountedGateHardware<T>, config: Config) -> Result<(), String> {
+    let db = open_database(&config.database).await;
+    let users = db.refresh_users().await.expect("Error loading users");
+    info!("Loaded {users} users");
+    let mut fk = FrankensteinWrapper::new(&config.telegram);
+    let http = config.http.clone();
+    let mqtt = config.mqtt.clone();
+    let auto_light = config.auto_light.clone();
+    let receiver = fk.start_getting_updates();
     let state = Arc::new(State {
-        hw: RefCountedGateHardware::<MockHardware>::new_mock(),
-        open_requests_waiting_confirmation: RwLock::new(HashMap::new()),
+        hw,
+        pending_actions: PendingActions::default(),
+        rate_limiter: RateLimiter::new(config.rate_limit.clone()),
+        callback_signer: CallbackSigner::random(),
         db,
+        poller_heartbeat: fk.poller_heartbeat(),
+        config: RwLock::new(config),
+        schedules_changed: Notify::new(),
     });
-    loop {
-        let updates = receiver.recv().await.expect("telegram disconnected!");
-        for update in updates {
-            let maybe_response = handle_update(Arc::clone(&state), update).await;
-            if let Some(response) = maybe_response {
-                let fk_clone = fk.clone();
-                tokio::spawn(async move { fk_clone.send_response(response).await });
-            }
-        }
+    if let Some(http) = http {
+        tokio::spawn(api::serve(Arc::clone(&state), http.listen));
+    }
+    if let Some(mqtt) = mqtt {
+        tokio::spawn(mqtt::run(Arc::clone(&state), mqtt));
+    }
+    if let Some(auto_light) = auto_light {
+        tokio::spawn(automation::run_sun_schedules(
+            Arc::clone(&state),
+            auto_light,
+        ));
     }
+    tokio::spawn(automation::run_schedules(Arc::clone(&state)));
+    tokio::spawn(reload_on_sighup(Arc::clone(&state)));
+    tokio::spawn(sweep_expired(Arc::clone(&state)));
+    tokio::spawn(health::notify_systemd(state.poller_heartbeat.clone()));
+    let controller = tokio::spawn(handle_updates(Arc::clone(&state), fk, receiver));
+    state.hw.watchdog(controller).await;
+    Err("controller failure".to_string())
 }
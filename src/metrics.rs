@@ -0,0 +1,80 @@
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    register_counter, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, Counter, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+
+pub static GATE_UNLOCKS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "gate_unlocks_total",
+        "Times each gate was unlocked, by gate id",
+        &["gate"]
+    )
+    .unwrap()
+});
+
+pub static SPOTLIGHT_ON_SECONDS: LazyLock<Counter> = LazyLock::new(|| {
+    register_counter!(
+        "spotlight_on_seconds_total",
+        "Total time the spotlight was on"
+    )
+    .unwrap()
+});
+
+pub static UPDATES_HANDLED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "telegram_updates_total",
+        "Telegram updates handled by outcome",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+pub static UPDATE_HANDLING_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "telegram_update_handling_seconds",
+        "Time spent handling a single Telegram update"
+    )
+    .unwrap()
+});
+
+pub static TELEGRAM_POLL_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "telegram_poll_seconds",
+        "Telegram getUpdates latency by result, long polling makes idle polls take ~30s",
+        &["result"],
+        vec![0.1, 0.5, 1.0, 5.0, 15.0, 30.0, 35.0, 60.0]
+    )
+    .unwrap()
+});
+
+pub static TELEGRAM_SEND_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "telegram_send_failures_total",
+        "Requests to Telegram given up on after their retries, by kind and error",
+        &["kind", "error"]
+    )
+    .unwrap()
+});
+
+pub static DB_LOOKUP_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("db_lookup_failures_total", "Failed user lookups in the DB").unwrap()
+});
+
+pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new().route("/metrics", get(metrics))
+}
+
+async fn metrics() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("Encoding metrics: {e}");
+    }
+    ([(CONTENT_TYPE, encoder.format_type().to_string())], buffer)
+}
@@ -0,0 +1,214 @@
+use crate::bot::{perform_gate_action, GateAction, Requester};
+use crate::config::{HardwareConfig, MqttConfig};
+use crate::hardware::RawHardware;
+use crate::State;
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+const DISCOVERY_PREFIX: &str = "homeassistant";
+const NODE_ID: &str = "gringos_gate";
+const AVAILABILITY_TOPIC: &str = "gringos_gate/availability";
+
+/// Device topics are `gringos_gate/{id}/{command}`, with the default single gate and light ids
+/// these are the same topics used before there could be several.
+fn device_topic(id: &str, command: &str) -> String {
+    format!("{NODE_ID}/{id}/{command}")
+}
+
+/// Connects to the broker, announces every gate and light using Home Assistant MQTT discovery
+/// and routes the commands received to the hardware. Runs forever, reconnecting on errors.
+pub async fn run<T: RawHardware>(state: Arc<State<T>>, config: MqttConfig) {
+    let mut options = MqttOptions::new(NODE_ID, config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        AVAILABILITY_TOPIC,
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = config.username {
+        options.set_credentials(username, config.password.unwrap_or_default());
+    }
+    let (client, event_loop) = AsyncClient::new(options, 10);
+    // pins can't change without a restart, so neither can the devices
+    let hardware = state.config.read().await.hardware.clone();
+    for (index, light) in hardware.lights.iter().enumerate() {
+        tokio::spawn(publish_spotlight_state(
+            Arc::clone(&state),
+            client.clone(),
+            index,
+            light.id.clone(),
+        ));
+    }
+    handle_events(state, client, event_loop, Arc::new(hardware)).await;
+}
+
+/// Maps a command topic and payload to the action on the device it names.
+fn parse_command(hardware: &HardwareConfig, topic: &str, payload: &str) -> Option<GateAction> {
+    let (id, command) = topic
+        .strip_prefix(NODE_ID)?
+        .strip_prefix('/')?
+        .split_once('/')?;
+    if let Some(gate) = hardware.gates.iter().position(|gate| gate.id == id) {
+        return match (command, payload) {
+            ("open", "PRESS") => Some(GateAction::Open(gate)),
+            _ => None,
+        };
+    }
+    let light = hardware.lights.iter().position(|light| light.id == id)?;
+    match (command, payload) {
+        ("set", "ON") => Some(GateAction::SpotlightOn(light)),
+        ("set", "OFF") => Some(GateAction::SpotlightOff(light)),
+        _ => None,
+    }
+}
+
+async fn handle_events<T: RawHardware>(
+    state: Arc<State<T>>,
+    client: AsyncClient,
+    mut event_loop: EventLoop,
+    hardware: Arc<HardwareConfig>,
+) {
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                let client = client.clone();
+                let hardware = Arc::clone(&hardware);
+                tokio::spawn(async move {
+                    if let Err(e) = announce(&client, &hardware).await {
+                        error!("Announcing to MQTT: {e}");
+                    }
+                });
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let payload = String::from_utf8_lossy(&publish.payload);
+                let Some(action) = parse_command(&hardware, &publish.topic, &payload) else {
+                    warn!("Ignoring MQTT message {payload} on {}", publish.topic);
+                    continue;
+                };
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    let requester = Requester::Automation("mqtt".to_string());
+                    perform_gate_action(&state, &requester, action).await;
+                });
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("MQTT connection: {e}");
+                tokio::time::sleep(Duration::from_secs(30)).await;
+            }
+        }
+    }
+}
+
+/// The default gate keeps the id it had when it was the only one, so Home Assistant doesn't see a
+/// new entity.
+fn gate_unique_id(id: &str) -> String {
+    if id == "gate" {
+        format!("{NODE_ID}_open")
+    } else {
+        format!("{NODE_ID}_{id}_open")
+    }
+}
+
+async fn announce(
+    client: &AsyncClient,
+    hardware: &HardwareConfig,
+) -> Result<(), rumqttc::ClientError> {
+    let device = json!({
+        "identifiers": [NODE_ID],
+        "name": "Gringos Gate",
+    });
+    for gate in &hardware.gates {
+        let command_topic = device_topic(&gate.id, "open");
+        let gate_config = json!({
+            "name": gate.name,
+            "unique_id": gate_unique_id(&gate.id),
+            "command_topic": command_topic,
+            "payload_press": "PRESS",
+            "availability_topic": AVAILABILITY_TOPIC,
+            "device": device,
+        });
+        client
+            .publish(
+                format!("{DISCOVERY_PREFIX}/button/{NODE_ID}/{}/config", gate.id),
+                QoS::AtLeastOnce,
+                true,
+                gate_config.to_string(),
+            )
+            .await?;
+        client.subscribe(command_topic, QoS::AtLeastOnce).await?;
+    }
+    for light in &hardware.lights {
+        let command_topic = device_topic(&light.id, "set");
+        let light_config = json!({
+            "name": light.name,
+            "unique_id": format!("{NODE_ID}_{}", light.id),
+            "command_topic": command_topic,
+            "state_topic": device_topic(&light.id, "state"),
+            "json_attributes_topic": device_topic(&light.id, "attributes"),
+            "availability_topic": AVAILABILITY_TOPIC,
+            "device": device,
+        });
+        client
+            .publish(
+                format!("{DISCOVERY_PREFIX}/switch/{NODE_ID}/{}/config", light.id),
+                QoS::AtLeastOnce,
+                true,
+                light_config.to_string(),
+            )
+            .await?;
+        client.subscribe(command_topic, QoS::AtLeastOnce).await?;
+    }
+    client
+        .publish(AVAILABILITY_TOPIC, QoS::AtLeastOnce, true, "online")
+        .await
+}
+
+/// Publishes the light state whenever it changes, and every minute while it is on so the
+/// remaining time attribute stays current.
+async fn publish_spotlight_state<T: RawHardware>(
+    state: Arc<State<T>>,
+    client: AsyncClient,
+    light: usize,
+    id: String,
+) {
+    let state_topic = device_topic(&id, "state");
+    let attributes_topic = device_topic(&id, "attributes");
+    let mut spotlight = state.hw.subscribe_spotlight(light);
+    loop {
+        let turn_off_at = *spotlight.borrow_and_update();
+        let (payload, remaining_seconds) = match turn_off_at {
+            Some(turn_off_at) => (
+                "ON",
+                turn_off_at
+                    .saturating_duration_since(Instant::now())
+                    .as_secs(),
+            ),
+            None => ("OFF", 0),
+        };
+        let attributes = json!({ "remaining_seconds": remaining_seconds }).to_string();
+        let published = async {
+            client
+                .publish(&state_topic, QoS::AtLeastOnce, true, payload)
+                .await?;
+            client
+                .publish(&attributes_topic, QoS::AtLeastOnce, true, attributes)
+                .await
+        };
+        if let Err(e) = published.await {
+            error!("Publishing {id} state: {e}");
+        }
+        let changed = spotlight.changed();
+        if turn_off_at.is_some() {
+            let _ = tokio::time::timeout(Duration::from_secs(60), changed).await;
+        } else if changed.await.is_err() {
+            return;
+        }
+    }
+}
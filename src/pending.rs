@@ -0,0 +1,237 @@
+use crate::database::DbUser;
+use crate::i18n::Locale;
+use crate::telegram::SenderProfile;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often [PendingActions::sweep] should run, entries are also dropped lazily when taken after
+/// expiring so this only bounds memory.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Something a user started that needs a further step before it happens.
+#[derive(Debug, Clone)]
+pub enum PendingAction {
+    /// Waiting for the user to type their PIN after "Abrir".
+    EnterPin { gate: usize },
+    /// Waiting for the user to press "Confirmar Abrir".
+    OpenGate { gate: usize },
+    /// Waiting for another resident to approve an opening at night, the entry belongs to the user
+    /// who wants to open.
+    ApproveOpen {
+        requester: DbUser,
+        locale: Locale,
+        gate: usize,
+    },
+    /// Waiting for an admin to grant guest access to or block a stranger, the entry belongs to the
+    /// stranger.
+    ReviewStranger {
+        profile: SenderProfile,
+        locale: Locale,
+    },
+}
+
+#[derive(Debug)]
+pub struct Pending<A> {
+    pub user_id: i64,
+    pub action: A,
+    expires_at: Instant,
+}
+
+/// Pending actions keyed by a random nonce, each usable once and only until it expires.
+/// The lock is never held across an await, so taking an entry doesn't block the hardware.
+pub struct PendingActions<A> {
+    entries: Mutex<HashMap<u64, Pending<A>>>,
+}
+
+impl<A> Default for PendingActions<A> {
+    fn default() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<A> PendingActions<A> {
+    /// Returns the nonce identifying the new entry.
+    pub fn insert(&self, user_id: i64, action: A, ttl: Duration) -> u64 {
+        let mut entries = self.entries.lock().expect("pending actions lock poisoned");
+        let nonce = loop {
+            let nonce = rand::random();
+            if !entries.contains_key(&nonce) {
+                break nonce;
+            }
+        };
+        entries.insert(
+            nonce,
+            Pending {
+                user_id,
+                action,
+                expires_at: Instant::now() + ttl,
+            },
+        );
+        nonce
+    }
+
+    /// Removes the entry, so it can only be taken once. Expired entries are removed too but
+    /// `None` is returned for them.
+    pub fn take(&self, nonce: u64) -> Option<Pending<A>> {
+        self.entries
+            .lock()
+            .expect("pending actions lock poisoned")
+            .remove(&nonce)
+            .filter(|pending| pending.expires_at > Instant::now())
+    }
+
+    pub fn contains_for_user(&self, user_id: i64, is_kind: impl Fn(&A) -> bool) -> bool {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .expect("pending actions lock poisoned")
+            .values()
+            .any(|pending| {
+                pending.user_id == user_id && pending.expires_at > now && is_kind(&pending.action)
+            })
+    }
+
+    /// Takes a live entry of the user for which `is_kind` holds, for steps that are answered with a
+    /// message instead of a button carrying the nonce.
+    pub fn take_for_user(&self, user_id: i64, is_kind: impl Fn(&A) -> bool) -> Option<Pending<A>> {
+        let mut entries = self.entries.lock().expect("pending actions lock poisoned");
+        let now = Instant::now();
+        let nonce = *entries
+            .iter()
+            .find(|(_, pending)| {
+                pending.user_id == user_id && pending.expires_at > now && is_kind(&pending.action)
+            })?
+            .0;
+        entries.remove(&nonce)
+    }
+
+    /// Like [PendingActions::take] but also returns expired entries, for whoever handles the
+    /// expiration itself. [PendingActions::sweep] keeps them around for a while, so this works
+    /// right after they expire.
+    pub fn remove(&self, nonce: u64) -> Option<Pending<A>> {
+        self.entries
+            .lock()
+            .expect("pending actions lock poisoned")
+            .remove(&nonce)
+    }
+
+    /// Drops every entry of the user for which `is_kind` holds, so a step answered with a message
+    /// can be asked for again without [PendingActions::take_for_user] picking an older one.
+    pub fn remove_for_user(&self, user_id: i64, is_kind: impl Fn(&A) -> bool) -> usize {
+        let mut entries = self.entries.lock().expect("pending actions lock poisoned");
+        let before = entries.len();
+        entries.retain(|_, pending| pending.user_id != user_id || !is_kind(&pending.action));
+        before - entries.len()
+    }
+
+    /// Drops entries that expired more than [SWEEP_INTERVAL] ago, returning how many were dropped.
+    pub fn sweep(&self) -> usize {
+        let mut entries = self.entries.lock().expect("pending actions lock poisoned");
+        let before = entries.len();
+        let now = Instant::now();
+        entries.retain(|_, pending| pending.expires_at + SWEEP_INTERVAL > now);
+        before - entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: i64 = 42;
+    const TTL: Duration = Duration::from_secs(30);
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Step {
+        Pin,
+        Confirm,
+    }
+
+    fn is_pin(step: &Step) -> bool {
+        *step == Step::Pin
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn entries_are_taken_once() {
+        let pending = PendingActions::default();
+        let nonce = pending.insert(USER, Step::Confirm, TTL);
+        let taken = pending.take(nonce).unwrap();
+        assert_eq!((taken.user_id, taken.action), (USER, Step::Confirm));
+        assert!(pending.take(nonce).is_none());
+        assert!(pending.take(nonce.wrapping_add(1)).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn entries_expire() {
+        let pending = PendingActions::default();
+        let first = pending.insert(USER, Step::Confirm, TTL);
+        let second = pending.insert(USER, Step::Confirm, TTL);
+        assert_ne!(first, second);
+        tokio::time::advance(TTL - Duration::from_millis(1)).await;
+        assert!(pending.take(first).is_some());
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(pending.take(second).is_none());
+        // taking it dropped it, even though it had expired
+        assert!(pending.remove(second).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn taking_for_a_user_filters_by_user_kind_and_expiry() {
+        let pending = PendingActions::default();
+        pending.insert(USER + 1, Step::Pin, TTL);
+        pending.insert(USER, Step::Confirm, TTL);
+        assert!(!pending.contains_for_user(USER, is_pin));
+        assert!(pending.take_for_user(USER, is_pin).is_none());
+
+        pending.insert(USER, Step::Pin, TTL);
+        assert!(pending.contains_for_user(USER, is_pin));
+        let taken = pending.take_for_user(USER, is_pin).unwrap();
+        assert_eq!((taken.user_id, taken.action), (USER, Step::Pin));
+        assert!(pending.take_for_user(USER, is_pin).is_none());
+        assert!(pending.take_for_user(USER + 1, is_pin).is_some());
+
+        pending.insert(USER, Step::Pin, TTL);
+        tokio::time::advance(TTL).await;
+        assert!(!pending.contains_for_user(USER, is_pin));
+        assert!(pending.take_for_user(USER, is_pin).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn removing_for_a_user_filters_by_user_and_kind() {
+        let pending = PendingActions::default();
+        pending.insert(USER, Step::Pin, TTL);
+        pending.insert(USER, Step::Pin, TTL);
+        let confirm = pending.insert(USER, Step::Confirm, TTL);
+        pending.insert(USER + 1, Step::Pin, TTL);
+        assert_eq!(pending.remove_for_user(USER, is_pin), 2);
+        assert!(!pending.contains_for_user(USER, is_pin));
+        assert!(pending.contains_for_user(USER + 1, is_pin));
+        assert!(pending.take(confirm).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn removing_returns_expired_entries() {
+        let pending = PendingActions::default();
+        let nonce = pending.insert(USER, Step::Confirm, TTL);
+        tokio::time::advance(TTL * 2).await;
+        assert_eq!(pending.remove(nonce).unwrap().action, Step::Confirm);
+        assert!(pending.remove(nonce).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sweeping_keeps_entries_for_a_while_after_they_expire() {
+        let pending = PendingActions::default();
+        let short = pending.insert(USER, Step::Confirm, TTL);
+        let long = pending.insert(USER, Step::Confirm, TTL * 10);
+        tokio::time::advance(TTL + SWEEP_INTERVAL - Duration::from_millis(1)).await;
+        assert_eq!(pending.sweep(), 0);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(pending.sweep(), 1);
+        assert!(pending.remove(short).is_none());
+        assert!(pending.take(long).is_some());
+    }
+}
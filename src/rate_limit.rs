@@ -0,0 +1,238 @@
+use crate::config::RateLimitConfig;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitKind {
+    /// Opening the gate, turning on the light and typing PINs.
+    GateAction,
+    /// Any update from users that are not in the user cache, checked before hitting the DB.
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allowed,
+    Limited,
+    /// Kept hitting the limit, the user was just banned for [RateLimitConfig::ban_secs].
+    Banned,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    /// Limited requests since the last allowed one.
+    violations: u32,
+}
+
+/// Token buckets per user and [LimitKind], refilled continuously at `per_minute` up to `burst`.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(i64, LimitKind), Bucket>>,
+    bans: Mutex<HashMap<i64, Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            bans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ban_duration(&self) -> Duration {
+        Duration::from_secs(self.config.ban_secs)
+    }
+
+    pub fn is_banned(&self, user_id: i64) -> bool {
+        self.bans
+            .lock()
+            .expect("bans lock poisoned")
+            .get(&user_id)
+            .is_some_and(|banned_until| *banned_until > Instant::now())
+    }
+
+    fn limits(&self, kind: LimitKind) -> (f64, f64) {
+        let (per_minute, burst) = match kind {
+            LimitKind::GateAction => (
+                self.config.gate_actions_per_minute,
+                self.config.gate_actions_burst,
+            ),
+            LimitKind::Unknown => (self.config.unknown_per_minute, self.config.unknown_burst),
+        };
+        (f64::from(per_minute) / 60.0, f64::from(burst))
+    }
+
+    /// Takes a token, banning the user after [RateLimitConfig::ban_after_violations] limited
+    /// requests in a row.
+    pub fn check(&self, user_id: i64, kind: LimitKind) -> Decision {
+        let (per_second, burst) = self.limits(kind);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("buckets lock poisoned");
+        let bucket = buckets.entry((user_id, kind)).or_insert(Bucket {
+            tokens: burst,
+            refilled_at: now,
+            violations: 0,
+        });
+        let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * per_second;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.violations = 0;
+            return Decision::Allowed;
+        }
+        bucket.violations += 1;
+        if bucket.violations < self.config.ban_after_violations {
+            return Decision::Limited;
+        }
+        bucket.violations = 0;
+        self.bans
+            .lock()
+            .expect("bans lock poisoned")
+            .insert(user_id, now + self.ban_duration());
+        Decision::Banned
+    }
+
+    /// Forgets buckets that are full again and bans that are over, so strangers don't pile up.
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .expect("buckets lock poisoned")
+            .retain(|(_, kind), bucket| {
+                let (per_second, burst) = self.limits(*kind);
+                let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * per_second;
+                bucket.tokens + refill < burst
+            });
+        self.bans
+            .lock()
+            .expect("bans lock poisoned")
+            .retain(|_, banned_until| *banned_until > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: i64 = 42;
+
+    /// 6 gate actions a minute, one every 10 seconds, after a burst of 2.
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            gate_actions_per_minute: 6,
+            gate_actions_burst: 2,
+            unknown_per_minute: 1,
+            unknown_burst: 1,
+            ban_after_violations: 3,
+            ban_secs: 3600,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn buckets_refill_over_time() {
+        let limiter = limiter();
+        assert_eq!(
+            limiter.check(USER, LimitKind::GateAction),
+            Decision::Allowed
+        );
+        assert_eq!(
+            limiter.check(USER, LimitKind::GateAction),
+            Decision::Allowed
+        );
+        assert_eq!(
+            limiter.check(USER, LimitKind::GateAction),
+            Decision::Limited
+        );
+        tokio::time::advance(Duration::from_secs(9)).await;
+        assert_eq!(
+            limiter.check(USER, LimitKind::GateAction),
+            Decision::Limited
+        );
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(
+            limiter.check(USER, LimitKind::GateAction),
+            Decision::Allowed
+        );
+        // Refilling stops at the burst.
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        assert_eq!(
+            limiter.check(USER, LimitKind::GateAction),
+            Decision::Allowed
+        );
+        assert_eq!(
+            limiter.check(USER, LimitKind::GateAction),
+            Decision::Allowed
+        );
+        assert_eq!(
+            limiter.check(USER, LimitKind::GateAction),
+            Decision::Limited
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn users_and_kinds_have_their_own_buckets() {
+        let limiter = limiter();
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Allowed);
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Limited);
+        assert_eq!(
+            limiter.check(USER + 1, LimitKind::Unknown),
+            Decision::Allowed
+        );
+        assert_eq!(
+            limiter.check(USER, LimitKind::GateAction),
+            Decision::Allowed
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeated_violations_ban_until_it_expires() {
+        let limiter = limiter();
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Allowed);
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Limited);
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Limited);
+        assert!(!limiter.is_banned(USER));
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Banned);
+        assert!(limiter.is_banned(USER));
+        assert!(!limiter.is_banned(USER + 1));
+
+        tokio::time::advance(Duration::from_secs(3599)).await;
+        assert!(limiter.is_banned(USER));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!limiter.is_banned(USER));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn allowed_requests_reset_the_violations() {
+        let limiter = limiter();
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Allowed);
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Limited);
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Limited);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Allowed);
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Limited);
+        assert_eq!(limiter.check(USER, LimitKind::Unknown), Decision::Limited);
+        assert!(!limiter.is_banned(USER));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sweeping_forgets_full_buckets_and_expired_bans() {
+        let limiter = limiter();
+        for _ in 0..4 {
+            limiter.check(USER, LimitKind::Unknown);
+        }
+        limiter.check(USER + 1, LimitKind::GateAction);
+        limiter.sweep();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        assert_eq!(limiter.bans.lock().unwrap().len(), 1);
+
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        limiter.sweep();
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+        assert!(limiter.bans.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+
+/// Degrees below the horizon at which the sun counts as risen or set, accounting for refraction
+/// and the size of the sun's disc.
+const HORIZON_DEGREES: f64 = -0.833;
+/// Julian date of 2000-01-01 12:00 UTC.
+const J2000: f64 = 2451545.0;
+/// Julian date of the unix epoch.
+const UNIX_EPOCH_JULIAN_DATE: f64 = 2440587.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SunDay {
+    Rises {
+        sunrise: DateTime<Utc>,
+        sunset: DateTime<Utc>,
+    },
+    /// Polar day, the sun doesn't set.
+    AlwaysUp,
+    /// Polar night, the sun doesn't rise.
+    AlwaysDown,
+}
+
+/// Sunrise and sunset on the local `date` at the location (degrees, north and east positive),
+/// using the sunrise equation, which is within a couple of minutes away from the poles.
+pub fn sun_day(date: NaiveDate, latitude: f64, longitude: f64) -> SunDay {
+    let j2000_date = NaiveDate::from_ymd_opt(2000, 1, 1).expect("valid date");
+    let days = (date - j2000_date).num_days() as f64;
+    let mean_solar_noon = days - longitude / 360.0;
+    let mean_anomaly = (357.5291 + 0.98560028 * mean_solar_noon).rem_euclid(360.0);
+    let anomaly = mean_anomaly.to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (mean_anomaly + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let solar_transit = J2000 + mean_solar_noon + 0.0053 * anomaly.sin()
+        - 0.0069 * (2.0 * ecliptic_longitude).sin();
+    let declination = (ecliptic_longitude.sin() * 23.4397_f64.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = (HORIZON_DEGREES.to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if cos_hour_angle > 1.0 {
+        return SunDay::AlwaysDown;
+    }
+    if cos_hour_angle < -1.0 {
+        return SunDay::AlwaysUp;
+    }
+    let half_day = cos_hour_angle.acos().to_degrees() / 360.0;
+    SunDay::Rises {
+        sunrise: from_julian_date(solar_transit - half_day),
+        sunset: from_julian_date(solar_transit + half_day),
+    }
+}
+
+/// The date at the location by the sun, which is what [sun_day] expects.
+pub fn local_date(now: DateTime<Utc>, longitude: f64) -> NaiveDate {
+    let offset = chrono::Duration::seconds((longitude / 15.0 * 3600.0) as i64);
+    (now + offset).date_naive()
+}
+
+pub fn is_dark(now: DateTime<Utc>, latitude: f64, longitude: f64) -> bool {
+    match sun_day(local_date(now, longitude), latitude, longitude) {
+        SunDay::Rises { sunrise, sunset } => now < sunrise || now >= sunset,
+        SunDay::AlwaysUp => false,
+        SunDay::AlwaysDown => true,
+    }
+}
+
+fn from_julian_date(julian_date: f64) -> DateTime<Utc> {
+    let unix_seconds = ((julian_date - UNIX_EPOCH_JULIAN_DATE) * 86400.0).round() as i64;
+    Utc.timestamp_opt(unix_seconds, 0)
+        .single()
+        .expect("sun times are close to the given date")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// São Paulo, as in gate.example.toml.
+    const LATITUDE: f64 = -23.55;
+    const LONGITUDE: f64 = -46.63;
+    /// Tromsø, north of the polar circle.
+    const POLAR_LATITUDE: f64 = 69.65;
+    const POLAR_LONGITUDE: f64 = 18.96;
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, month, day).unwrap()
+    }
+
+    fn utc(month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn assert_close(actual: DateTime<Utc>, expected: DateTime<Utc>) {
+        let error = (actual - expected).num_seconds().abs();
+        assert!(
+            error <= 120,
+            "{actual} is not within 2 minutes of {expected}"
+        );
+    }
+
+    #[test]
+    fn matches_known_sunrise_and_sunset() {
+        // Almanac times, 06:47 to 17:28 and 05:17 to 18:52 in UTC-3.
+        for (day, expected_sunrise, expected_sunset) in [
+            (date(6, 21), utc(6, 21, 9, 47), utc(6, 21, 20, 28)),
+            (date(12, 21), utc(12, 21, 8, 17), utc(12, 21, 21, 52)),
+        ] {
+            let SunDay::Rises { sunrise, sunset } = sun_day(day, LATITUDE, LONGITUDE) else {
+                panic!("the sun rises in São Paulo");
+            };
+            assert_close(sunrise, expected_sunrise);
+            assert_close(sunset, expected_sunset);
+        }
+    }
+
+    #[test]
+    fn polar_day_and_night() {
+        assert_eq!(
+            sun_day(date(6, 21), POLAR_LATITUDE, POLAR_LONGITUDE),
+            SunDay::AlwaysUp
+        );
+        assert_eq!(
+            sun_day(date(12, 21), POLAR_LATITUDE, POLAR_LONGITUDE),
+            SunDay::AlwaysDown
+        );
+        assert!(!is_dark(utc(6, 21, 0, 0), POLAR_LATITUDE, POLAR_LONGITUDE));
+        assert!(is_dark(utc(12, 21, 12, 0), POLAR_LATITUDE, POLAR_LONGITUDE));
+    }
+
+    #[test]
+    fn dark_from_sunset_until_sunrise() {
+        let SunDay::Rises { sunrise, sunset } = sun_day(date(6, 21), LATITUDE, LONGITUDE) else {
+            panic!("the sun rises in São Paulo");
+        };
+        let second = chrono::Duration::seconds(1);
+        assert!(is_dark(sunrise - second, LATITUDE, LONGITUDE));
+        assert!(!is_dark(sunrise, LATITUDE, LONGITUDE));
+        assert!(!is_dark(sunset - second, LATITUDE, LONGITUDE));
+        assert!(is_dark(sunset, LATITUDE, LONGITUDE));
+    }
+
+    #[test]
+    fn local_date_follows_the_sun() {
+        // 01:00 UTC is still the previous evening in São Paulo.
+        assert_eq!(local_date(utc(6, 21, 1, 0), LONGITUDE), date(6, 20));
+        assert_eq!(local_date(utc(6, 21, 4, 0), LONGITUDE), date(6, 21));
+    }
+}
@@ -1,24 +1,74 @@
 use async_trait::async_trait;
+use std::fmt::{Debug, Formatter};
 use std::time::Duration;
-use tracing::debug;
+use thiserror::Error;
 
+#[cfg(test)]
+pub mod fake;
 mod frankenstein_impl;
+mod outbox;
 pub use frankenstein_impl::FrankensteinWrapper;
+pub use outbox::Outbox;
+
 #[derive(Debug, Clone)]
 pub struct TelegramUpdate {
     pub user_id: u64,
+    /// Language of the user's Telegram client, like "pt-br".
+    pub language_code: Option<String>,
+    pub sender: SenderProfile,
     pub content: Content,
 }
 
-#[derive(Debug, Clone)]
+/// Who sent an update, as shown in their Telegram profile.
+#[derive(Debug, Clone, Default)]
+pub struct SenderProfile {
+    pub username: Option<String>,
+    pub first_name: String,
+    pub last_name: Option<String>,
+}
+
+impl SenderProfile {
+    pub fn full_name(&self) -> String {
+        match &self.last_name {
+            Some(last_name) => format!("{} {}", self.first_name, last_name),
+            None => self.first_name.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum Content {
-    Message(String),
-    Button {
+    Message {
         text: String,
+        message_id: i32,
+    },
+    Button {
+        callback_data: String,
         callback_query_id: String,
     },
 }
 
+/// Messages may be PINs, so only their length is shown.
+impl Debug for Content {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Content::Message { text, message_id } => f
+                .debug_struct("Message")
+                .field("chars", &text.chars().count())
+                .field("message_id", message_id)
+                .finish(),
+            Content::Button {
+                callback_data,
+                callback_query_id,
+            } => f
+                .debug_struct("Button")
+                .field("callback_data", callback_data)
+                .field("callback_query_id", callback_query_id)
+                .finish(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum TelegramResponse {
     DeletableOutgoingMessage(DeletableOutgoingMessage),
@@ -29,7 +79,15 @@ pub enum TelegramResponse {
 pub struct OutgoingMessage {
     pub user_id: i64,
     pub message: String,
-    pub buttons: Option<Vec<String>>,
+    pub buttons: Option<Vec<Button>>,
+}
+
+/// Inline keyboard button, the label is what the user sees and the callback data is what we get
+/// back when it is pressed.
+#[derive(Debug, Clone)]
+pub struct Button {
+    pub label: String,
+    pub callback_data: String,
 }
 
 #[derive(Debug, Clone)]
@@ -45,47 +103,37 @@ pub struct DeletableOutgoingMessage {
     pub delete_after: Option<Duration>,
 }
 
+#[derive(Debug, Error)]
+pub enum TelegramError {
+    /// Flood control, the same request works again after `retry_after`.
+    #[error("rate limited by Telegram, retry after {retry_after:?}")]
+    RateLimited { retry_after: Duration },
+    /// Already deleted or too old, for messages and callback queries alike.
+    #[error("message not found: {0}")]
+    MessageNotFound(String),
+    /// The user blocked the bot or never talked to it.
+    #[error("not allowed to message the user: {0}")]
+    Forbidden(String),
+    #[error("Telegram refused the request with {code}: {description}")]
+    Api { code: u64, description: String },
+    /// Telegram couldn't be reached or answered with something unexpected.
+    #[error("could not talk to Telegram: {0}")]
+    Unreachable(String),
+}
+
+impl TelegramError {
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            TelegramError::RateLimited { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
 #[async_trait]
 pub trait TelegramInterface {
     fn start_getting_updates(&mut self) -> tokio::sync::mpsc::Receiver<Vec<TelegramUpdate>>;
-    async fn send_response(&self, response: TelegramResponse) -> Result<(), String> {
-        match response {
-            TelegramResponse::DeletableOutgoingMessage(msg) => {
-                self.send_message_and_sleep_then_delete_if_needed(msg).await
-            }
-            TelegramResponse::ButtonAnswer(button_answer) => {
-                self.send_button_answer(button_answer).await
-            }
-        }
-    }
-    async fn send_button_answer(&self, msg: ButtonAnswer) -> Result<(), String>;
-    async fn send_message(&self, msg: OutgoingMessage) -> Result<i32, String>;
-    async fn delete_message(&self, user_id: i64, message_id: i32) -> Result<(), String>;
-    async fn send_message_and_sleep_then_delete_if_needed(
-        &self,
-        msg: DeletableOutgoingMessage,
-    ) -> Result<(), String> {
-        let answer_button_task = async {
-            if let Some(button_answer) = msg.button_answer {
-                debug!("Sending button answer {button_answer:#?}",);
-                self.send_button_answer(button_answer).await
-            } else {
-                Ok(())
-            }
-        };
-        let (_, msg_id) = tokio::try_join!(
-            answer_button_task,
-            self.send_message(msg.outgoing_msg.clone())
-        )?;
-        if let Some(deleted_after) = msg.delete_after {
-            tokio::time::sleep(deleted_after).await;
-            debug!(
-                "Deleting original msg with id: {} from chat {}",
-                msg_id, msg.outgoing_msg.user_id
-            );
-            self.delete_message(msg.outgoing_msg.user_id, msg_id)
-                .await?;
-        };
-        Ok(())
-    }
+    async fn send_button_answer(&self, msg: ButtonAnswer) -> Result<(), TelegramError>;
+    async fn send_message(&self, msg: OutgoingMessage) -> Result<i32, TelegramError>;
+    async fn delete_message(&self, user_id: i64, message_id: i32) -> Result<(), TelegramError>;
 }
@@ -0,0 +1,215 @@
+use crate::telegram::{
+    ButtonAnswer, Content, OutgoingMessage, SenderProfile, TelegramError, TelegramInterface,
+    TelegramUpdate,
+};
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Something the bot did through [FakeTelegram], in the order it happened.
+#[derive(Debug, Clone)]
+enum Sent {
+    Message {
+        message_id: i32,
+        msg: OutgoingMessage,
+    },
+    ButtonAnswer(ButtonAnswer),
+    Deletion {
+        user_id: i64,
+        message_id: i32,
+        at: Instant,
+    },
+}
+
+/// The API methods [FakeTelegram::fail_next] can make fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    AnswerCallbackQuery,
+    SendMessage,
+    DeleteMessage,
+}
+
+#[derive(Default)]
+struct Recording {
+    sent: Vec<Sent>,
+    failures: Vec<(Method, TelegramError)>,
+    last_message_id: i32,
+    last_callback_query_id: u64,
+}
+
+/// Stands in for the Telegram API: tests script the updates with [FakeTelegram::send_text] and
+/// [FakeTelegram::press], and read back what was sent, answered and deleted. Clones share the recording, like clones
+/// of [crate::telegram::FrankensteinWrapper] share the connection.
+#[derive(Clone)]
+pub struct FakeTelegram {
+    recording: Arc<Mutex<Recording>>,
+    updates: mpsc::Sender<Vec<TelegramUpdate>>,
+    receiver: Arc<Mutex<Option<mpsc::Receiver<Vec<TelegramUpdate>>>>>,
+}
+
+impl FakeTelegram {
+    pub fn new() -> Self {
+        let (updates, receiver) = mpsc::channel(16);
+        Self {
+            recording: Arc::default(),
+            updates,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+        }
+    }
+
+    fn recording(&self) -> MutexGuard<'_, Recording> {
+        self.recording.lock().expect("recording lock poisoned")
+    }
+
+    /// Makes the next call to the method fail with the error, calls failing in the order given.
+    pub fn fail_next(&self, method: Method, error: TelegramError) {
+        self.recording().failures.push((method, error));
+    }
+
+    pub async fn push(&self, update: TelegramUpdate) {
+        self.updates
+            .send(vec![update])
+            .await
+            .expect("nobody is getting updates");
+    }
+
+    /// Returns the id of the user's message, to check it was deleted with
+    /// [FakeTelegram::deleted_at].
+    pub async fn send_text(&self, user_id: i64, text: &str) -> i32 {
+        let message_id = {
+            let mut recording = self.recording();
+            recording.last_message_id += 1;
+            recording.last_message_id
+        };
+        let content = Content::Message {
+            text: text.to_string(),
+            message_id,
+        };
+        self.push(update(user_id, content)).await;
+        message_id
+    }
+
+    /// Returns the callback query id, to find the answer with [FakeTelegram::answer_to].
+    pub async fn press(&self, user_id: i64, callback_data: &str) -> String {
+        let callback_query_id = {
+            let mut recording = self.recording();
+            recording.last_callback_query_id += 1;
+            recording.last_callback_query_id.to_string()
+        };
+        let content = Content::Button {
+            callback_data: callback_data.to_string(),
+            callback_query_id: callback_query_id.clone(),
+        };
+        self.push(update(user_id, content)).await;
+        callback_query_id
+    }
+
+    /// Messages sent to the user, oldest first, with their ids.
+    pub fn messages_to(&self, user_id: i64) -> Vec<(i32, OutgoingMessage)> {
+        self.recording()
+            .sent
+            .iter()
+            .filter_map(|sent| match sent {
+                Sent::Message {
+                    message_id, msg, ..
+                } if msg.user_id == user_id => Some((*message_id, msg.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn last_message_to(&self, user_id: i64) -> Option<OutgoingMessage> {
+        self.messages_to(user_id).pop().map(|(_, msg)| msg)
+    }
+
+    pub fn answer_to(&self, callback_query_id: &str) -> Option<String> {
+        self.recording().sent.iter().find_map(|sent| match sent {
+            Sent::ButtonAnswer(answer) if answer.callback_query_id == callback_query_id => {
+                Some(answer.message.clone())
+            }
+            _ => None,
+        })
+    }
+
+    pub fn deleted_at(&self, user_id: i64, message_id: i32) -> Option<Instant> {
+        self.recording().sent.iter().find_map(|sent| match sent {
+            Sent::Deletion {
+                user_id: chat,
+                message_id: deleted,
+                at,
+            } if *chat == user_id && *deleted == message_id => Some(*at),
+            _ => None,
+        })
+    }
+}
+
+impl Recording {
+    fn take_failure(&mut self, method: Method) -> Result<(), TelegramError> {
+        match self
+            .failures
+            .iter()
+            .position(|(failing, _)| *failing == method)
+        {
+            Some(index) => Err(self.failures.remove(index).1),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for FakeTelegram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn update(user_id: i64, content: Content) -> TelegramUpdate {
+    TelegramUpdate {
+        user_id: user_id as u64,
+        language_code: Some("pt-br".to_string()),
+        sender: SenderProfile {
+            username: None,
+            first_name: format!("User {user_id}"),
+            last_name: None,
+        },
+        content,
+    }
+}
+
+#[async_trait]
+impl TelegramInterface for FakeTelegram {
+    fn start_getting_updates(&mut self) -> mpsc::Receiver<Vec<TelegramUpdate>> {
+        self.receiver
+            .lock()
+            .expect("receiver lock poisoned")
+            .take()
+            .expect("already getting updates")
+    }
+
+    async fn send_button_answer(&self, answer: ButtonAnswer) -> Result<(), TelegramError> {
+        let mut recording = self.recording();
+        recording.take_failure(Method::AnswerCallbackQuery)?;
+        recording.sent.push(Sent::ButtonAnswer(answer));
+        Ok(())
+    }
+
+    async fn send_message(&self, msg: OutgoingMessage) -> Result<i32, TelegramError> {
+        let mut recording = self.recording();
+        recording.take_failure(Method::SendMessage)?;
+        recording.last_message_id += 1;
+        let message_id = recording.last_message_id;
+        recording.sent.push(Sent::Message { message_id, msg });
+        Ok(message_id)
+    }
+
+    async fn delete_message(&self, user_id: i64, message_id: i32) -> Result<(), TelegramError> {
+        let mut recording = self.recording();
+        recording.take_failure(Method::DeleteMessage)?;
+        recording.sent.push(Sent::Deletion {
+            user_id,
+            message_id,
+            at: Instant::now(),
+        });
+        Ok(())
+    }
+}
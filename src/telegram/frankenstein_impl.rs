@@ -1,11 +1,18 @@
-use crate::telegram::{ButtonAnswer, Content, OutgoingMessage, TelegramInterface, TelegramUpdate};
+use crate::config::TelegramConfig;
+use crate::health::Heartbeat;
+use crate::metrics;
+use crate::telegram::{
+    ButtonAnswer, Content, OutgoingMessage, SenderProfile, TelegramError, TelegramInterface,
+    TelegramUpdate,
+};
 use async_trait::async_trait;
 use frankenstein::{
     AllowedUpdate, AnswerCallbackQueryParams, AsyncApi, AsyncTelegramApi, CallbackQuery, ChatId,
-    DeleteMessageParams, InlineKeyboardButton, InlineKeyboardMarkup, Message, ReplyMarkup,
-    SendMessageParams, Update, UpdateContent,
+    DeleteMessageParams, ErrorResponse, InlineKeyboardButton, InlineKeyboardMarkup, Message,
+    ReplyMarkup, SendMessageParams, Update, UpdateContent, User,
 };
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
 
 use tracing::{debug, error, info, trace};
@@ -13,25 +20,34 @@ use tracing::{debug, error, info, trace};
 #[derive(Clone)]
 pub struct FrankensteinWrapper {
     telegram: Arc<AsyncApi>,
+    poller_heartbeat: Heartbeat,
+    stale_message_cutoff_secs: u64,
 }
 
 impl FrankensteinWrapper {
-    pub fn new() -> Self {
-        let token = std::env::var("BOT_API_TOKEN").expect("No bot api token");
-        let api = AsyncApi::new(&token);
+    pub fn new(config: &TelegramConfig) -> Self {
+        let api = AsyncApi::new(&config.bot_token);
         Self {
             telegram: Arc::new(api),
+            poller_heartbeat: Heartbeat::default(),
+            stale_message_cutoff_secs: config.stale_message_cutoff_secs,
         }
     }
+
+    /// Beats every time a poll for updates is done, failed or not.
+    pub fn poller_heartbeat(&self) -> Heartbeat {
+        self.poller_heartbeat.clone()
+    }
 }
 
 struct FrankensteinReceiverWrapper {
     telegram: Arc<AsyncApi>,
     last_processed_update_id: i64,
+    stale_message_cutoff_secs: u64,
 }
 
 impl FrankensteinReceiverWrapper {
-    pub async fn get_updates(&mut self) -> Result<Vec<TelegramUpdate>, String> {
+    pub async fn get_updates(&mut self) -> Result<Vec<TelegramUpdate>, TelegramError> {
         let current_update_id = self.last_processed_update_id;
         let update_params = frankenstein::GetUpdatesParams {
             offset: Some(current_update_id + 1),
@@ -39,12 +55,12 @@ impl FrankensteinReceiverWrapper {
             timeout: Some(30),
             allowed_updates: Some(vec![AllowedUpdate::Message, AllowedUpdate::CallbackQuery]),
         };
-        let result: Vec<Update> = self
-            .telegram
-            .get_updates(&update_params)
-            .await
-            .map_err(|e| e.to_string())?
-            .result;
+        let poll_start = std::time::Instant::now();
+        let result = self.telegram.get_updates(&update_params).await;
+        metrics::TELEGRAM_POLL_SECONDS
+            .with_label_values(&[if result.is_ok() { "ok" } else { "error" }])
+            .observe(poll_start.elapsed().as_secs_f64());
+        let result: Vec<Update> = result.map_err(telegram_error)?.result;
 
         let updates: Vec<UpdateContent> = result
             .into_iter()
@@ -55,29 +71,36 @@ impl FrankensteinReceiverWrapper {
                 update.content
             })
             .collect();
-        debug!("Raw Updates: {:?}", updates);
         let updates: Vec<TelegramUpdate> = updates
             .into_iter()
             .filter_map(|update| match update {
                 UpdateContent::Message(Message {
+                    message_id,
                     text: Some(message_text),
                     date: msg_unix_timestamp,
                     from: Some(user),
                     ..
                 }) => {
                     let oldest_timestamp_to_get_msg_for =
-                        u64::try_from(chrono::Utc::now().timestamp() - 10)
-                            .expect("weird timestamp");
+                        u64::try_from(chrono::Utc::now().timestamp())
+                            .expect("weird timestamp")
+                            .saturating_sub(self.stale_message_cutoff_secs);
                     if msg_unix_timestamp < oldest_timestamp_to_get_msg_for {
                         info!(
-                            "Discarding older messages: {} from {:?}",
-                            message_text, user
+                            "Discarding an old message of {} chars from {}",
+                            message_text.chars().count(),
+                            user.id
                         );
                         None
                     } else {
                         Some(TelegramUpdate {
                             user_id: user.id,
-                            content: Content::Message(message_text),
+                            language_code: user.language_code.clone(),
+                            sender: sender_profile(*user),
+                            content: Content::Message {
+                                text: message_text,
+                                message_id,
+                            },
                         })
                     }
                 }
@@ -88,13 +111,24 @@ impl FrankensteinReceiverWrapper {
                     ..
                 }) => Some(TelegramUpdate {
                     user_id: user.id,
+                    language_code: user.language_code.clone(),
+                    sender: sender_profile(user),
                     content: Content::Button {
-                        text: callback_data,
+                        callback_data,
                         callback_query_id: id,
                     },
                 }),
-                x => {
-                    info!("Ignoring update: {:?}", x);
+                // Only messages and buttons are asked for, and their text may be a PIN.
+                UpdateContent::Message(_) => {
+                    info!("Ignoring a message without text or sender");
+                    None
+                }
+                UpdateContent::CallbackQuery(_) => {
+                    info!("Ignoring a button press without data");
+                    None
+                }
+                _ => {
+                    info!("Ignoring an update of a kind not asked for");
                     None
                 }
             })
@@ -103,17 +137,69 @@ impl FrankensteinReceiverWrapper {
     }
 }
 
+/// Used when Telegram rate limits without saying for how long.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+/// How long the poller waits after other errors.
+const POLL_ERROR_PAUSE: Duration = Duration::from_secs(30);
+
+fn telegram_error(e: frankenstein::Error) -> TelegramError {
+    match e {
+        frankenstein::Error::Api(ErrorResponse {
+            error_code: 429,
+            parameters,
+            ..
+        }) => TelegramError::RateLimited {
+            retry_after: parameters
+                .and_then(|parameters| parameters.retry_after)
+                .map_or(DEFAULT_RETRY_AFTER, |secs| Duration::from_secs(secs.into())),
+        },
+        frankenstein::Error::Api(ErrorResponse {
+            error_code: 400,
+            description,
+            ..
+        }) if description.contains("not found") || description.contains("too old") => {
+            TelegramError::MessageNotFound(description)
+        }
+        frankenstein::Error::Api(ErrorResponse {
+            error_code: 403,
+            description,
+            ..
+        }) => TelegramError::Forbidden(description),
+        frankenstein::Error::Api(ErrorResponse {
+            error_code,
+            description,
+            ..
+        }) => TelegramError::Api {
+            code: error_code,
+            description,
+        },
+        e => TelegramError::Unreachable(e.to_string()),
+    }
+}
+
+fn sender_profile(user: User) -> SenderProfile {
+    SenderProfile {
+        username: user.username,
+        first_name: user.first_name,
+        last_name: user.last_name,
+    }
+}
+
 #[async_trait]
 impl TelegramInterface for FrankensteinWrapper {
     fn start_getting_updates(&mut self) -> Receiver<Vec<TelegramUpdate>> {
         let mut telegram_receiver = FrankensteinReceiverWrapper {
             telegram: Arc::clone(&self.telegram),
             last_processed_update_id: 0,
+            stale_message_cutoff_secs: self.stale_message_cutoff_secs,
         };
         let (tx, rx) = tokio::sync::mpsc::channel::<Vec<TelegramUpdate>>(10);
+        let heartbeat = self.poller_heartbeat.clone();
         tokio::spawn(async move {
             loop {
-                match telegram_receiver.get_updates().await {
+                let result = telegram_receiver.get_updates().await;
+                heartbeat.beat();
+                match result {
                     Ok(updates) => {
                         debug!("Got {} updates!", updates.len());
                         trace!("{:#?}", updates);
@@ -125,7 +211,7 @@ impl TelegramInterface for FrankensteinWrapper {
                     }
                     Err(e) => {
                         error!("Getting updates: {e}");
-                        tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+                        tokio::time::sleep(e.retry_after().unwrap_or(POLL_ERROR_PAUSE)).await;
                         continue;
                     }
                 }
@@ -134,7 +220,7 @@ impl TelegramInterface for FrankensteinWrapper {
         rx
     }
 
-    async fn send_button_answer(&self, msg: ButtonAnswer) -> Result<(), String> {
+    async fn send_button_answer(&self, msg: ButtonAnswer) -> Result<(), TelegramError> {
         self.telegram
             .answer_callback_query(&AnswerCallbackQueryParams {
                 callback_query_id: msg.callback_query_id,
@@ -144,19 +230,19 @@ impl TelegramInterface for FrankensteinWrapper {
                 cache_time: Some(3),
             })
             .await
-            .map_err(|e| e.to_string())?;
+            .map_err(telegram_error)?;
         Ok(())
     }
 
-    async fn send_message(&self, msg: OutgoingMessage) -> Result<i32, String> {
+    async fn send_message(&self, msg: OutgoingMessage) -> Result<i32, TelegramError> {
         let buttons = msg.buttons.map(|buttons| {
             let buttons: Vec<InlineKeyboardButton> = buttons
                 .into_iter()
                 .map(|single_button| InlineKeyboardButton {
-                    text: single_button.clone(),
+                    text: single_button.label,
                     url: None,
                     login_url: None,
-                    callback_data: Some(single_button),
+                    callback_data: Some(single_button.callback_data),
                     web_app: None,
                     switch_inline_query: None,
                     switch_inline_query_current_chat: None,
@@ -165,8 +251,9 @@ impl TelegramInterface for FrankensteinWrapper {
                     pay: None,
                 })
                 .collect();
+            // rows of two, so several gates and lights don't squeeze into one row
             ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
-                inline_keyboard: vec![buttons],
+                inline_keyboard: buttons.chunks(2).map(<[_]>::to_vec).collect(),
             })
         });
         let msg = SendMessageParams {
@@ -185,20 +272,96 @@ impl TelegramInterface for FrankensteinWrapper {
             .telegram
             .send_message(&msg)
             .await
-            .map_err(|e| e.to_string())?
+            .map_err(telegram_error)?
             .result
             .message_id;
         Ok(msg_id)
     }
 
-    async fn delete_message(&self, user_id: i64, message_id: i32) -> Result<(), String> {
+    async fn delete_message(&self, user_id: i64, message_id: i32) -> Result<(), TelegramError> {
         self.telegram
             .delete_message(&DeleteMessageParams {
                 chat_id: ChatId::Integer(user_id),
                 message_id,
             })
             .await
-            .map_err(|e| e.to_string())?;
+            .map_err(telegram_error)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frankenstein::ResponseParameters;
+
+    fn api_error(
+        error_code: u64,
+        description: &str,
+        parameters: Option<ResponseParameters>,
+    ) -> frankenstein::Error {
+        frankenstein::Error::Api(ErrorResponse {
+            ok: false,
+            description: description.to_string(),
+            error_code,
+            parameters,
+        })
+    }
+
+    #[test]
+    fn flood_control_carries_retry_after() {
+        let parameters = ResponseParameters {
+            migrate_to_chat_id: None,
+            retry_after: Some(7),
+        };
+        let e = telegram_error(api_error(
+            429,
+            "Too Many Requests: retry after 7",
+            Some(parameters),
+        ));
+        assert_eq!(e.retry_after(), Some(Duration::from_secs(7)));
+        let e = telegram_error(api_error(429, "Too Many Requests", None));
+        assert_eq!(e.retry_after(), Some(DEFAULT_RETRY_AFTER));
+    }
+
+    #[test]
+    fn classifies_api_errors() {
+        let e = telegram_error(api_error(
+            400,
+            "Bad Request: message to delete not found",
+            None,
+        ));
+        assert!(matches!(e, TelegramError::MessageNotFound(_)));
+        let e = telegram_error(api_error(
+            400,
+            "Bad Request: query is too old and response timeout expired or query ID is invalid",
+            None,
+        ));
+        assert!(matches!(e, TelegramError::MessageNotFound(_)));
+        let e = telegram_error(api_error(
+            403,
+            "Forbidden: bot was blocked by the user",
+            None,
+        ));
+        assert!(matches!(e, TelegramError::Forbidden(_)));
+        let e = telegram_error(api_error(400, "Bad Request: chat not found", None));
+        assert!(matches!(e, TelegramError::MessageNotFound(_)));
+        assert_eq!(e.retry_after(), None);
+    }
+
+    #[test]
+    fn logged_updates_hide_message_text() {
+        let update = TelegramUpdate {
+            user_id: 1,
+            language_code: None,
+            sender: SenderProfile::default(),
+            content: Content::Message {
+                text: "4321".to_string(),
+                message_id: 7,
+            },
+        };
+        let logged = format!("{:#?}", vec![update]);
+        assert!(!logged.contains("4321"), "{logged}");
+        assert!(logged.contains("message_id: 7"), "{logged}");
+    }
+}
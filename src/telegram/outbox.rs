@@ -0,0 +1,427 @@
+use crate::metrics;
+use crate::telegram::{
+    ButtonAnswer, OutgoingMessage, TelegramError, TelegramInterface, TelegramResponse,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::{debug, error, warn};
+
+/// How hard to try delivering one kind of request before giving up on it.
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    attempts: u32,
+    /// Wait before the first retry, doubled for each one after.
+    backoff: Duration,
+    /// Flood control asking to wait longer than this means the request is given up, as it would
+    /// be stale by the time it got through.
+    max_retry_after: Duration,
+}
+
+/// Telegram only takes answers for a few seconds after the button is pressed.
+const ANSWER_POLICY: RetryPolicy = RetryPolicy {
+    attempts: 2,
+    backoff: Duration::from_secs(1),
+    max_retry_after: Duration::from_secs(5),
+};
+
+const MESSAGE_POLICY: RetryPolicy = RetryPolicy {
+    attempts: 4,
+    backoff: Duration::from_secs(2),
+    max_retry_after: Duration::from_secs(60),
+};
+
+/// Confirmation messages shouldn't stay in the chat, so deletions are tried the longest.
+const DELETION_POLICY: RetryPolicy = RetryPolicy {
+    attempts: 6,
+    backoff: Duration::from_secs(5),
+    max_retry_after: Duration::from_secs(5 * 60),
+};
+
+#[derive(Debug)]
+enum Request {
+    Answer(ButtonAnswer),
+    Message {
+        msg: OutgoingMessage,
+        delete_after: Option<Duration>,
+    },
+    Deletion {
+        user_id: i64,
+        message_id: i32,
+    },
+}
+
+impl Request {
+    fn kind(&self) -> &'static str {
+        match self {
+            Request::Answer(_) => "answer",
+            Request::Message { .. } => "message",
+            Request::Deletion { .. } => "deletion",
+        }
+    }
+
+    fn policy(&self) -> RetryPolicy {
+        match self {
+            Request::Answer(_) => ANSWER_POLICY,
+            Request::Message { .. } => MESSAGE_POLICY,
+            Request::Deletion { .. } => DELETION_POLICY,
+        }
+    }
+
+    /// Returns the id of the message sent, if any.
+    async fn send<I: TelegramInterface>(&self, telegram: &I) -> Result<Option<i32>, TelegramError> {
+        match self {
+            Request::Answer(answer) => telegram.send_button_answer(answer.clone()).await?,
+            Request::Message { msg, .. } => {
+                return telegram.send_message(msg.clone()).await.map(Some)
+            }
+            Request::Deletion {
+                user_id,
+                message_id,
+            } => match telegram.delete_message(*user_id, *message_id).await {
+                Err(TelegramError::MessageNotFound(_)) => {
+                    debug!("Message {message_id} in chat {user_id} was already deleted");
+                }
+                result => result?,
+            },
+        }
+        Ok(None)
+    }
+}
+
+/// Queue of responses for Telegram, first tried in order by a single task. Requests failing for a
+/// reason that could go away are retried in a task of their own following the [RetryPolicy] of
+/// their kind, as are the deletions of [DeletableOutgoingMessage]s, so they don't hold back the
+/// answers queued after them. Only flood control holds back everything, see [FloodControl].
+/// Clones share the queue.
+///
+/// [DeletableOutgoingMessage]: crate::telegram::DeletableOutgoingMessage
+#[derive(Clone)]
+pub struct Outbox {
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl Outbox {
+    /// Spawns the task sending the responses, so must be called from within the runtime. The task
+    /// stops once every handle is dropped.
+    pub fn spawn<I: TelegramInterface + Send + Sync + 'static>(telegram: I) -> Outbox {
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let sender = Sender {
+            telegram: Arc::new(telegram),
+            flood_control: Arc::default(),
+        };
+        tokio::spawn(sender.deliver(receiver));
+        Outbox { requests }
+    }
+
+    pub fn send(&self, response: TelegramResponse) {
+        match response {
+            TelegramResponse::ButtonAnswer(answer) => self.push(Request::Answer(answer)),
+            TelegramResponse::DeletableOutgoingMessage(msg) => {
+                if let Some(answer) = msg.button_answer {
+                    self.push(Request::Answer(answer));
+                }
+                self.push(Request::Message {
+                    msg: msg.outgoing_msg,
+                    delete_after: msg.delete_after,
+                });
+            }
+        }
+    }
+
+    /// Deletes a message the user sent, like one holding their PIN.
+    pub fn delete(&self, user_id: i64, message_id: i32) {
+        self.push(Request::Deletion {
+            user_id,
+            message_id,
+        });
+    }
+
+    fn push(&self, request: Request) {
+        if let Err(e) = self.requests.send(request) {
+            error!("Outbox task is gone, dropping {:?}", e.0);
+        }
+    }
+}
+
+/// Telegram's flood control applies to the whole bot, so after a `retry_after` nothing is sent
+/// until it has passed.
+#[derive(Default)]
+struct FloodControl {
+    resume_at: Mutex<Option<Instant>>,
+}
+
+impl FloodControl {
+    async fn wait(&self) {
+        loop {
+            let resume_at = *self.resume_at.lock().expect("flood control lock poisoned");
+            match resume_at {
+                Some(resume_at) if resume_at > Instant::now() => sleep_until(resume_at).await,
+                _ => return,
+            }
+        }
+    }
+
+    fn hold(&self, retry_after: Duration) {
+        let until = Instant::now() + retry_after;
+        let mut resume_at = self.resume_at.lock().expect("flood control lock poisoned");
+        if resume_at.is_none_or(|resume_at| resume_at < until) {
+            *resume_at = Some(until);
+        }
+    }
+}
+
+struct Sender<I> {
+    telegram: Arc<I>,
+    flood_control: Arc<FloodControl>,
+}
+
+impl<I> Clone for Sender<I> {
+    fn clone(&self) -> Self {
+        Self {
+            telegram: Arc::clone(&self.telegram),
+            flood_control: Arc::clone(&self.flood_control),
+        }
+    }
+}
+
+impl<I: TelegramInterface + Send + Sync + 'static> Sender<I> {
+    async fn deliver(self, mut requests: mpsc::UnboundedReceiver<Request>) {
+        while let Some(request) = requests.recv().await {
+            match self.attempt(&request).await {
+                Ok(message_id) => self.sent(&request, message_id),
+                Err(e) => {
+                    let sender = self.clone();
+                    tokio::spawn(async move { sender.retry(request, e).await });
+                }
+            }
+        }
+    }
+
+    /// Waits out flood control first, and starts it when Telegram asks to.
+    async fn attempt(&self, request: &Request) -> Result<Option<i32>, TelegramError> {
+        self.flood_control.wait().await;
+        let result = request.send(self.telegram.as_ref()).await;
+        if let Err(TelegramError::RateLimited { retry_after }) = &result {
+            self.flood_control.hold(*retry_after);
+        }
+        result
+    }
+
+    /// Schedules the deletion of messages that should disappear.
+    fn sent(&self, request: &Request, message_id: Option<i32>) {
+        if let (
+            Request::Message {
+                msg,
+                delete_after: Some(delete_after),
+            },
+            Some(message_id),
+        ) = (request, message_id)
+        {
+            let deletion = Request::Deletion {
+                user_id: msg.user_id,
+                message_id,
+            };
+            let delete_after = *delete_after;
+            let sender = self.clone();
+            tokio::spawn(async move {
+                sleep(delete_after).await;
+                if let Err(e) = sender.attempt(&deletion).await {
+                    sender.retry(deletion, e).await;
+                }
+            });
+        }
+    }
+
+    /// Keeps trying a request that failed with `e` on its first attempt.
+    async fn retry(&self, request: Request, mut e: TelegramError) {
+        let policy = request.policy();
+        let mut backoff = policy.backoff;
+        for attempt in 2..=policy.attempts {
+            let Some(wait) = retry_wait(&e, policy, backoff) else {
+                break;
+            };
+            warn!(
+                "Sending {} failed on attempt {}, retrying in {wait:?}: {e}",
+                request.kind(),
+                attempt - 1
+            );
+            sleep(wait).await;
+            match self.attempt(&request).await {
+                Ok(message_id) => return self.sent(&request, message_id),
+                Err(failed) => e = failed,
+            }
+            backoff *= 2;
+        }
+        give_up(&request, e);
+    }
+}
+
+fn give_up(request: &Request, e: TelegramError) {
+    error!("Giving up on sending {request:?}: {e}");
+    metrics::TELEGRAM_SEND_FAILURES
+        .with_label_values(&[request.kind(), error_label(&e)])
+        .inc();
+}
+
+/// `None` when retrying can't help. Flood control waits are left to [FloodControl].
+fn retry_wait(e: &TelegramError, policy: RetryPolicy, backoff: Duration) -> Option<Duration> {
+    match e {
+        TelegramError::RateLimited { retry_after } => {
+            (*retry_after <= policy.max_retry_after).then_some(Duration::ZERO)
+        }
+        TelegramError::Unreachable(_) => Some(backoff),
+        TelegramError::Api { code, .. } if *code >= 500 => Some(backoff),
+        _ => None,
+    }
+}
+
+fn error_label(e: &TelegramError) -> &'static str {
+    match e {
+        TelegramError::RateLimited { .. } => "rate_limited",
+        TelegramError::MessageNotFound(_) => "not_found",
+        TelegramError::Forbidden(_) => "forbidden",
+        TelegramError::Api { .. } => "api",
+        TelegramError::Unreachable(_) => "unreachable",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telegram::fake::{FakeTelegram, Method};
+    use crate::telegram::DeletableOutgoingMessage;
+    use tokio::time::Instant;
+
+    const USER: i64 = 7;
+
+    fn message(text: &str, delete_after: Option<Duration>) -> TelegramResponse {
+        TelegramResponse::DeletableOutgoingMessage(DeletableOutgoingMessage {
+            outgoing_msg: OutgoingMessage {
+                user_id: USER,
+                message: text.to_string(),
+                buttons: None,
+            },
+            button_answer: None,
+            delete_after,
+        })
+    }
+
+    fn texts(telegram: &FakeTelegram) -> Vec<String> {
+        telegram
+            .messages_to(USER)
+            .into_iter()
+            .map(|(_, msg)| msg.message)
+            .collect()
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flood_control_holds_back_the_queue() {
+        let telegram = FakeTelegram::new();
+        telegram.fail_next(
+            Method::SendMessage,
+            TelegramError::RateLimited {
+                retry_after: Duration::from_secs(7),
+            },
+        );
+        let outbox = Outbox::spawn(telegram.clone());
+        outbox.send(message("first", None));
+        outbox.send(message("second", None));
+        tokio::time::sleep(Duration::from_millis(6999)).await;
+        assert!(texts(&telegram).is_empty());
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let mut sent = texts(&telegram);
+        sent.sort();
+        assert_eq!(sent, vec!["first", "second"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_deletions_are_retried() {
+        let telegram = FakeTelegram::new();
+        telegram.fail_next(
+            Method::DeleteMessage,
+            TelegramError::Unreachable("connection reset".to_string()),
+        );
+        let outbox = Outbox::spawn(telegram.clone());
+        let start = Instant::now();
+        outbox.send(message("confirm?", Some(Duration::from_secs(5))));
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        let (message_id, _) = telegram.messages_to(USER)[0];
+        assert!(telegram.deleted_at(USER, message_id).is_none());
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let deleted_at = telegram.deleted_at(USER, message_id).expect("deleted");
+        assert_eq!(
+            deleted_at - start,
+            DELETION_POLICY.backoff + Duration::from_secs(5)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permanent_failures_are_counted_and_skipped() {
+        let failures = || {
+            metrics::TELEGRAM_SEND_FAILURES
+                .with_label_values(&["message", "forbidden"])
+                .get()
+        };
+        let before = failures();
+        let telegram = FakeTelegram::new();
+        telegram.fail_next(
+            Method::SendMessage,
+            TelegramError::Forbidden("bot was blocked by the user".to_string()),
+        );
+        let outbox = Outbox::spawn(telegram.clone());
+        outbox.send(message("blocked", None));
+        outbox.send(message("unblocked", None));
+        settle().await;
+        assert_eq!(texts(&telegram), vec!["unblocked"]);
+        assert!(failures() > before);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_deletions_dont_hold_back_answers() {
+        let telegram = FakeTelegram::new();
+        for _ in 0..DELETION_POLICY.attempts {
+            telegram.fail_next(
+                Method::DeleteMessage,
+                TelegramError::Unreachable("connection reset".to_string()),
+            );
+        }
+        let outbox = Outbox::spawn(telegram.clone());
+        outbox.send(message("confirm?", Some(Duration::from_secs(5))));
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        outbox.send(TelegramResponse::ButtonAnswer(ButtonAnswer {
+            callback_query_id: "1".to_string(),
+            message: "done".to_string(),
+        }));
+        settle().await;
+        assert_eq!(telegram.answer_to("1").as_deref(), Some("done"));
+        let (message_id, _) = telegram.messages_to(USER)[0];
+        assert!(telegram.deleted_at(USER, message_id).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_answers_are_given_up() {
+        let telegram = FakeTelegram::new();
+        telegram.fail_next(
+            Method::AnswerCallbackQuery,
+            TelegramError::RateLimited {
+                retry_after: Duration::from_secs(30),
+            },
+        );
+        let outbox = Outbox::spawn(telegram.clone());
+        outbox.send(TelegramResponse::ButtonAnswer(ButtonAnswer {
+            callback_query_id: "1".to_string(),
+            message: "done".to_string(),
+        }));
+        outbox.send(message("menu", None));
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        settle().await;
+        assert!(telegram.answer_to("1").is_none());
+        assert_eq!(texts(&telegram), vec!["menu"]);
+    }
+}